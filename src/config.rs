@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::irc::network::ServerAddress;
+use crate::irc::NickServ;
+
+/// Settings of `config.toml`, every value can be overridden on the command line
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // Network to use without --network
+    pub network: Option<String>,
+    pub nickname: Option<String>,
+    pub timeout_seconds: Option<u64>,
+    pub output_dir: Option<PathBuf>,
+    // How many bots to download from at the same time
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub networks: HashMap<String, Network>,
+}
+
+/// An IRC network, e.g. `[networks.rizon]`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Network {
+    // Servers to try in order, with an optional port or ircs:// for TLS
+    #[serde(default)]
+    pub servers: Vec<String>,
+    // Port for servers without one
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: bool,
+    pub nickname: Option<String>,
+    // Account to identify with, the nickname is used if it is not set
+    pub nickserv_account: Option<String>,
+    pub nickserv_password: Option<String>,
+    #[serde(default)]
+    pub channels: Vec<String>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/xdcc-cli/config.toml`, or in `~/.config` if XDG_CONFIG_HOME is not set
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_home.join("xdcc-cli").join("config.toml"))
+    }
+
+    /// Loads the config file, a missing file is only an error if its path was given explicitly
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read config file {}", path.display())),
+        };
+        toml::from_str(&content).with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// The network with the given name, or the default network of the config
+    pub fn network(&self, name: Option<&str>) -> Result<Option<&Network>> {
+        match name.or(self.network.as_deref()) {
+            Some(name) => self.networks.get(name).map(Some)
+                .ok_or_else(|| anyhow!("Unknown network {}, it is not defined in the config file", name)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Network {
+    /// The servers with the port of the network added, if they do not have one
+    pub fn server_addresses(&self) -> Result<Vec<String>> {
+        self.servers.iter()
+            .map(|server| {
+                let address = ServerAddress::parse_with_port(server, self.tls, self.port)
+                    .context("Invalid server in config file")?;
+                // without a port of the network, --tls may still choose the default port
+                Ok(if self.port.is_some() { address.to_string() } else { server.clone() })
+            })
+            .collect()
+    }
+
+    pub fn nickserv(&self) -> Option<NickServ> {
+        self.nickserv_password.as_ref().map(|password| NickServ {
+            account: self.nickserv_account.clone(),
+            password: password.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r##"
+network = "rizon"
+nickname = "default-nick"
+timeout_seconds = 60
+output_dir = "/data/downloads"
+concurrency = 2
+
+[networks.rizon]
+servers = ["irc.rizon.net", "irc.rizon.io:6660", "ircs://[2001:db8::1]"]
+port = 6697
+tls = true
+nickname = "my-nick"
+nickserv_password = "secret"
+channels = ["#nibl", "#news"]
+
+[networks.libera]
+servers = ["2001:db8::2", "irc.libera.chat"]
+nickserv_account = "account"
+nickserv_password = "secret"
+"##;
+
+    fn network(servers: &[&str], port: Option<u16>, tls: bool) -> Network {
+        Network {
+            servers: servers.iter().map(|server| server.to_string()).collect(),
+            port,
+            tls,
+            ..Network::default()
+        }
+    }
+
+    #[test]
+    fn parses_the_config_file() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        assert_eq!(config.nickname.as_deref(), Some("default-nick"));
+        assert_eq!(config.timeout_seconds, Some(60));
+        assert_eq!(config.output_dir, Some(PathBuf::from("/data/downloads")));
+        assert_eq!(config.concurrency, Some(2));
+
+        let rizon = config.network(None).unwrap().unwrap();
+        assert!(rizon.tls);
+        assert_eq!(rizon.nickname.as_deref(), Some("my-nick"));
+        assert_eq!(rizon.channels, vec!["#nibl", "#news"]);
+        let libera = config.network(Some("libera")).unwrap().unwrap();
+        assert!(!libera.tls && libera.port.is_none() && libera.channels.is_empty());
+        assert!(config.network(Some("efnet")).is_err());
+        assert!(Config::default().network(None).unwrap().is_none());
+
+        assert!(toml::from_str::<Config>("nick = \"typo\"").is_err());
+        assert!(toml::from_str::<Config>("[networks.rizon]\nserver = \"irc.rizon.net\"").is_err());
+    }
+
+    #[test]
+    fn adds_the_port_of_the_network() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        assert_eq!(config.network(None).unwrap().unwrap().server_addresses().unwrap(),
+                   vec!["ircs://irc.rizon.net:6697", "ircs://irc.rizon.io:6660", "ircs://[2001:db8::1]:6697"]);
+        // without a port, the servers are used as they are
+        assert_eq!(config.network(Some("libera")).unwrap().unwrap().server_addresses().unwrap(),
+                   vec!["2001:db8::2", "irc.libera.chat"]);
+
+        let plain = network(&["irc.example.net", "[::1]", "::1", "irc://irc.example.net"], Some(7000), false);
+        assert_eq!(plain.server_addresses().unwrap(),
+                   vec!["irc.example.net:7000", "[::1]:7000", "[::1]:7000", "irc.example.net:7000"]);
+        assert!(network(&["irc.example.net:port"], Some(7000), false).server_addresses().is_err());
+        assert!(network(&["2001:db8::1:6697x"], None, false).server_addresses().is_err());
+    }
+
+    #[test]
+    fn reads_nickserv_credentials() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let rizon = config.network(None).unwrap().unwrap().nickserv().unwrap();
+        assert_eq!((rizon.account, rizon.password.as_str()), (None, "secret"));
+        let libera = config.network(Some("libera")).unwrap().unwrap().nickserv().unwrap();
+        assert_eq!(libera.account.as_deref(), Some("account"));
+        assert!(network(&[], None, false).nickserv().is_none());
+    }
+}
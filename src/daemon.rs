@@ -0,0 +1,353 @@
+use std::fmt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::timeout;
+
+use crate::irc::command::xdcc::Xdcc;
+use crate::package_downloader::{validate_command, Control, ControlLink, DownloadCommand, PackageDownloader};
+use crate::queue_file::QueueEntry;
+use crate::summary::PackResult;
+
+// Clients send a single request right after connecting
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request sent to the daemon as a single line of JSON
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    Add { commands: Vec<String> },
+    List,
+    Cancel { id: u64 },
+    Pause,
+    Resume,
+}
+
+/// The reply of the daemon to a request
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Added { ids: Vec<u64> },
+    Items { paused: bool, items: Vec<QueueItem> },
+    Ok,
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemStatus {
+    // Waiting for its bot or downloading
+    Queued,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl fmt::Display for ItemStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            ItemStatus::Queued => "queued",
+            ItemStatus::Done => "done",
+            ItemStatus::Failed => "failed",
+            ItemStatus::Cancelled => "cancelled",
+        };
+        f.pad(status)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueItem {
+    pub id: u64,
+    // The command as it was added, including its overrides
+    pub command: String,
+    pub status: ItemStatus,
+    pub error: Option<String>,
+}
+
+/// The queue of the daemon, saved to disk after every change
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Queue {
+    next_id: u64,
+    paused: bool,
+    items: Vec<QueueItem>,
+}
+
+impl Queue {
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse queue file {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read queue file {}", path.display())),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        // replace the file at once, so it is never left half written
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&temporary, path)
+            .with_context(|| format!("Failed to write queue file {}", path.display()))
+    }
+
+    fn item_mut(&mut self, id: u64) -> Option<&mut QueueItem> {
+        self.items.iter_mut().find(|item| item.id == id)
+    }
+}
+
+/// Parses a command for the daemon, which only knows the network and channel it joined.
+/// Clients may only choose directories inside the output directory of the daemon.
+fn parse_command(command: &str, output_dir: &Path) -> Result<DownloadCommand> {
+    let entry = QueueEntry::try_from(command)?;
+    if entry.server.is_some() || entry.channel.is_some() {
+        bail!("The daemon does not support server= and channel=, start another daemon instead: {}", command);
+    }
+    if matches!(entry.command, Xdcc::Info(..)) {
+        bail!("The daemon only downloads packs: {}", command);
+    }
+    let inside_output_dir = |dir: &PathBuf| dir.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !entry.output_dir.as_ref().is_none_or(inside_output_dir) {
+        bail!("output-dir= must be a relative path inside the output directory of the daemon: {}", command);
+    }
+    validate_command(&entry.command)?;
+    let mut command = DownloadCommand::from(entry);
+    command.output_dir = command.output_dir.map(|dir| output_dir.join(dir));
+    Ok(command)
+}
+
+struct Daemon {
+    queue: Queue,
+    queue_path: PathBuf,
+    // output-dir= of the commands is relative to it
+    output_dir: PathBuf,
+    control: UnboundedSender<Control>,
+}
+
+impl Daemon {
+    fn save(&self) {
+        if let Err(e) = self.queue.save(&self.queue_path) {
+            log::error!("{:?}", e);
+        }
+    }
+
+    fn add(&mut self, commands: Vec<String>) -> Result<Response> {
+        // add all commands or none of them
+        let parsed = commands.iter()
+            .map(|command| parse_command(command, &self.output_dir))
+            .collect::<Result<Vec<_>>>()?;
+        let mut ids = Vec::new();
+        for (text, command) in commands.into_iter().zip(parsed) {
+            let id = self.queue.next_id;
+            self.queue.next_id += 1;
+            self.queue.items.push(QueueItem { id, command: text, status: ItemStatus::Queued, error: None });
+            self.control.send(Control::Add(id, command))?;
+            ids.push(id);
+        }
+        self.save();
+        Ok(Response::Added { ids })
+    }
+
+    fn cancel(&mut self, id: u64) -> Result<Response> {
+        let item = self.queue.item_mut(id).ok_or_else(|| anyhow!("There is no item {}", id))?;
+        if item.status != ItemStatus::Queued {
+            bail!("Item {} is not queued anymore", id);
+        }
+        item.status = ItemStatus::Cancelled;
+        self.control.send(Control::Cancel(id))?;
+        self.save();
+        Ok(Response::Ok)
+    }
+
+    fn set_paused(&mut self, paused: bool) -> Result<Response> {
+        self.queue.paused = paused;
+        self.control.send(if paused { Control::Pause } else { Control::Resume })?;
+        self.save();
+        Ok(Response::Ok)
+    }
+
+    fn handle_request(&mut self, request: Request) -> Response {
+        let result = match request {
+            Request::Add { commands } => self.add(commands),
+            Request::List => Ok(Response::Items { paused: self.queue.paused, items: self.queue.items.clone() }),
+            Request::Cancel { id } => self.cancel(id),
+            Request::Pause => self.set_paused(true),
+            Request::Resume => self.set_paused(false),
+        };
+        result.unwrap_or_else(|e| Response::Error { message: format!("{:#}", e) })
+    }
+
+    async fn handle_connection(&mut self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        timeout(REQUEST_TIMEOUT, BufReader::new(reader).read_line(&mut line)).await
+            .map_err(|_| anyhow!("Timed out waiting for the request"))??;
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => self.handle_request(request),
+            Err(e) => Response::Error { message: format!("Invalid request: {}", e) },
+        };
+        writer.write_all((serde_json::to_string(&response)? + "\n").as_bytes()).await?;
+        Ok(())
+    }
+
+    fn handle_finished(&mut self, id: u64, results: Vec<PackResult>) {
+        let item = match self.queue.item_mut(id) {
+            // cancelled items keep their status
+            Some(item) if item.status == ItemStatus::Queued => item,
+            _ => return,
+        };
+        let failed = results.iter().filter(|result| !result.succeeded()).collect::<Vec<_>>();
+        match failed.first() {
+            Some(first) => {
+                item.status = ItemStatus::Failed;
+                item.error = Some(format!("{} of {} packs failed: {}", failed.len(), results.len(),
+                                          first.error.as_deref().unwrap_or_default()));
+            }
+            None => item.status = ItemStatus::Done,
+        }
+        log::info!("Item {} finished: {:?}", id, item.status);
+        self.save();
+    }
+}
+
+/// Removes the socket of a previous daemon, unless that daemon is still running
+async fn remove_stale_socket(socket_path: &Path) -> Result<()> {
+    if !socket_path.exists() {
+        return Ok(());
+    }
+    if UnixStream::connect(socket_path).await.is_ok() {
+        bail!("A daemon is already listening on {}", socket_path.display());
+    }
+    std::fs::remove_file(socket_path)?;
+    Ok(())
+}
+
+/// A directory in the temporary directory which only the current user can access, for systems without
+/// $XDG_RUNTIME_DIR. Other users could otherwise create the socket first and receive the commands.
+pub fn private_temp_dir() -> Result<PathBuf> {
+    // SAFETY: getuid has no preconditions and cannot fail
+    let uid = unsafe { libc::getuid() };
+    let dir = std::env::temp_dir().join(format!("xdcc-cli-{}", uid));
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => return Ok(dir),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to create {}", dir.display())),
+    }
+    let metadata = std::fs::symlink_metadata(&dir)
+        .with_context(|| format!("Failed to check {}", dir.display()))?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        bail!("Refusing to use {}, which belongs to another user or can be accessed by other users, \
+            use --socket instead", dir.display());
+    }
+    Ok(dir)
+}
+
+/// Binds the socket in a new directory which only we can access and moves it into place once only we can use it,
+/// so other users of the host cannot connect in between
+fn bind_private(socket_path: &Path) -> Result<UnixListener> {
+    let filename = socket_path.file_name()
+        .ok_or_else(|| anyhow!("Invalid socket path {}", socket_path.display()))?;
+    let dir = socket_path.with_file_name(format!(".xdcc-cli-{}", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    let temporary = dir.join(filename);
+    let result = UnixListener::bind(&temporary)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            std::fs::set_permissions(&temporary, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&temporary, socket_path)?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_file(&temporary);
+    let _ = std::fs::remove_dir(&dir);
+    result.with_context(|| format!("Failed to listen on {}", socket_path.display()))
+}
+
+/// Downloads the queued commands and the ones added over the socket until interrupted
+pub async fn run_daemon(downloader: PackageDownloader, queue_path: PathBuf, output_dir: PathBuf,
+                        socket_path: &Path) -> Result<()> {
+    remove_stale_socket(socket_path).await?;
+    // other users of the host must not control the daemon
+    let listener = bind_private(socket_path)?;
+    log::info!("Listening on {}.", socket_path.display());
+
+    let (control, control_receiver) = unbounded_channel();
+    let (finished_sender, mut finished) = unbounded_channel();
+    let downloader = downloader.with_control(ControlLink { control: control_receiver, finished: finished_sender });
+    let mut daemon = Daemon { queue: Queue::load(&queue_path)?, queue_path, output_dir, control };
+
+    // continue where the last daemon stopped
+    if daemon.queue.paused {
+        daemon.control.send(Control::Pause)?;
+    }
+    for item in daemon.queue.items.iter_mut().filter(|item| item.status == ItemStatus::Queued) {
+        match parse_command(&item.command, &daemon.output_dir) {
+            Ok(command) => daemon.control.send(Control::Add(item.id, command))?,
+            // e.g. written by another version, the other items can still be downloaded
+            Err(e) => {
+                log::warn!("Skipping item {} of the queue: {}", item.id, e);
+                item.status = ItemStatus::Failed;
+                item.error = Some(e.to_string());
+            }
+        }
+    }
+    daemon.save();
+
+    let mut downloads = tokio::spawn(downloader.download_packages());
+    let result = loop {
+        tokio::select! {
+            connection = listener.accept() => {
+                let (stream, _) = connection?;
+                if let Err(e) = daemon.handle_connection(stream).await {
+                    log::warn!("Failed to handle request: {:?}", e);
+                }
+            }
+            Some((id, results)) = finished.recv() => daemon.handle_finished(id, results),
+            // the downloader only stops on its own if the connection was closed
+            result = &mut downloads => break result.map_err(anyhow::Error::from).and_then(|result| result),
+            _ = tokio::signal::ctrl_c() => break Ok(()),
+        }
+    };
+    // queued items are downloaded by the next daemon
+    let _ = std::fs::remove_file(socket_path);
+    result
+}
+
+/// Sends a request to the daemon and waits for its response
+pub async fn send_request(socket_path: &Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(socket_path).await
+        .with_context(|| format!("Failed to connect to the daemon on {}, is it running?", socket_path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all((serde_json::to_string(request)? + "\n").as_bytes()).await?;
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_out_of_range_packs() {
+        assert!(parse_command("/msg bot xdcc send #99999999999", Path::new("downloads")).is_err());
+    }
+
+    #[test]
+    fn keeps_output_dirs_inside_the_output_dir() {
+        let command = parse_command("/msg bot xdcc send #1 output-dir=anime/new", Path::new("downloads")).unwrap();
+        assert_eq!(command.output_dir, Some(PathBuf::from("downloads/anime/new")));
+        for output_dir in ["/etc", "..", "anime/../../home", "~/.ssh"] {
+            let command = format!("/msg bot xdcc send #1 output-dir={}", output_dir);
+            // `~` is not expanded, so it is just a directory name
+            assert_eq!(parse_command(&command, Path::new("downloads")).is_err(), output_dir != "~/.ssh");
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref PLACEHOLDER_REGEX: Regex = Regex::new(r#"\{([^{}]*)\}"#).unwrap();
+}
+
+const PLACEHOLDERS: [&str; 4] = ["bot", "date", "filename", "pack"];
+
+/// What to do if the target file of a download already exists
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ExistingFilePolicy {
+    // Keep the existing file and do not download
+    Skip,
+    // Replace the existing file
+    Overwrite,
+    // Download to a new file with a numbered suffix
+    Rename,
+    // Continue partial files, skip complete ones
+    Resume,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DownloadTarget {
+    // Download to the path, starting at the given position
+    Download(PathBuf, u64),
+    // The file already exists and should not be downloaded
+    Skip(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadPaths {
+    output_dir: PathBuf,
+    template: String,
+    existing: ExistingFilePolicy,
+}
+
+/// Replaces anything in a value sent by the bot, which could escape the output directory
+fn escape_value(value: &str) -> String {
+    let value = value.replace(['/', '\\'], "_");
+    match value.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => value,
+    }
+}
+
+/// Replaces a leading `~` with the home directory, shells do not expand it in config files
+fn expand_home(path: PathBuf, home: Option<PathBuf>) -> PathBuf {
+    match (path.strip_prefix("~"), home) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path,
+    }
+}
+
+fn renamed_path(path: &Path, index: u32) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let filename = match path.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, index),
+    };
+    path.with_file_name(filename)
+}
+
+impl DownloadPaths {
+    /// Creates the download paths, the template may contain {bot}, {date}, {filename} and {pack}
+    pub fn new(output_dir: PathBuf, template: String, existing: ExistingFilePolicy) -> Result<Self> {
+        for captures in PLACEHOLDER_REGEX.captures_iter(&template) {
+            if !PLACEHOLDERS.contains(&&captures[1]) {
+                bail!("Unknown placeholder {} in filename template, expected one of {{{}}}",
+                    &captures[0], PLACEHOLDERS.join("}, {"));
+            }
+        }
+        let relative = Path::new(&template);
+        if relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            bail!("Filename template must be a relative path without '..': {}", template);
+        }
+        Ok(Self {
+            output_dir: expand_home(output_dir, std::env::var_os("HOME").map(PathBuf::from)),
+            template,
+            existing,
+        })
+    }
+
+    /// The same paths in another output directory
+    pub fn with_output_dir(&self, output_dir: PathBuf) -> Self {
+        Self {
+            output_dir,
+            ..self.clone()
+        }
+    }
+
+    /// Expands the filename template for a download and makes sure it stays in the output directory
+    pub fn expand(&self, bot: &str, pack: Option<u32>, filename: &str) -> Result<PathBuf> {
+        let relative = PLACEHOLDER_REGEX.replace_all(&self.template, |captures: &regex::Captures| {
+            match &captures[1] {
+                "bot" => escape_value(bot),
+                "date" => chrono::Local::now().format("%Y-%m-%d").to_string(),
+                "filename" => escape_value(filename),
+                "pack" => pack.map(|pack| pack.to_string()).unwrap_or_else(|| "unknown".to_string()),
+                _ => unreachable!(),
+            }
+        });
+        let relative = Path::new(relative.as_ref());
+        if relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            bail!("Refusing to write {} outside of the output directory", relative.display());
+        }
+        if self.output_dir == Path::new(".") {
+            return Ok(relative.to_path_buf());
+        }
+        Ok(self.output_dir.join(relative))
+    }
+
+    /// Decides where and from which position to download, based on the existing file policy
+    pub async fn resolve(&self, path: PathBuf, file_size: u64) -> Result<DownloadTarget> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let existing_size = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Ok(DownloadTarget::Download(path, 0)),
+        };
+
+        let target = match self.existing {
+            ExistingFilePolicy::Skip => DownloadTarget::Skip(path),
+            ExistingFilePolicy::Overwrite => DownloadTarget::Download(path, 0),
+            ExistingFilePolicy::Rename => DownloadTarget::Download(self.free_path(&path).await, 0),
+            ExistingFilePolicy::Resume if existing_size == file_size => DownloadTarget::Skip(path),
+            ExistingFilePolicy::Resume if existing_size < file_size => DownloadTarget::Download(path, existing_size),
+            ExistingFilePolicy::Resume => {
+                log::warn!("{} is larger than the offered file, downloading to a new file.", path.display());
+                DownloadTarget::Download(self.free_path(&path).await, 0)
+            }
+        };
+        Ok(target)
+    }
+
+    async fn free_path(&self, path: &Path) -> PathBuf {
+        let mut index = 1;
+        loop {
+            let candidate = renamed_path(path, index);
+            if tokio::fs::metadata(&candidate).await.is_err() {
+                return candidate;
+            }
+            index += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for the files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xdcc-cli-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn paths(output_dir: &Path, template: &str, existing: ExistingFilePolicy) -> DownloadPaths {
+        DownloadPaths::new(output_dir.to_path_buf(), template.to_string(), existing).unwrap()
+    }
+
+    async fn resolve(dir: &Path, existing: ExistingFilePolicy, file_size: u64) -> DownloadTarget {
+        paths(dir, "{filename}", existing).resolve(dir.join("file.mkv"), file_size).await.unwrap()
+    }
+
+    #[test]
+    fn expands_templates() {
+        let nested = paths(Path::new("downloads"), "{bot}/{pack}-{filename}", ExistingFilePolicy::Resume);
+        assert_eq!(nested.expand("bot", Some(7), "file.mkv").unwrap(), PathBuf::from("downloads/bot/7-file.mkv"));
+        assert_eq!(nested.expand("bot", None, "list.txt").unwrap(), PathBuf::from("downloads/bot/unknown-list.txt"));
+        // values sent by the bot cannot add path components
+        assert_eq!(nested.expand("../bot", Some(1), "a/b").unwrap(), PathBuf::from("downloads/.._bot/1-a_b"));
+        let current = paths(Path::new("."), "{filename}", ExistingFilePolicy::Resume);
+        assert_eq!(current.expand("bot", Some(1), "..").unwrap(), PathBuf::from("_"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let new = |template: &str| {
+            DownloadPaths::new(PathBuf::from("."), template.to_string(), ExistingFilePolicy::Skip)
+        };
+        assert!(new("{name}").is_err());
+        assert!(new("../{filename}").is_err());
+        assert!(new("/tmp/{filename}").is_err());
+        assert!(new("{date}/{bot}/{filename}").is_ok());
+    }
+
+    #[test]
+    fn expands_the_home_directory() {
+        let home = Some(PathBuf::from("/home/user"));
+        assert_eq!(expand_home(PathBuf::from("~/downloads"), home.clone()), PathBuf::from("/home/user/downloads"));
+        assert_eq!(expand_home(PathBuf::from("~"), home.clone()), PathBuf::from("/home/user"));
+        assert_eq!(expand_home(PathBuf::from("~other/downloads"), home.clone()), PathBuf::from("~other/downloads"));
+        assert_eq!(expand_home(PathBuf::from("downloads/~"), home), PathBuf::from("downloads/~"));
+        assert_eq!(expand_home(PathBuf::from("~/downloads"), None), PathBuf::from("~/downloads"));
+    }
+
+    #[tokio::test]
+    async fn applies_the_existing_file_policy() {
+        let dir = test_dir("policy");
+        let path = dir.join("file.mkv");
+        assert_eq!(resolve(&dir, ExistingFilePolicy::Skip, 10).await, DownloadTarget::Download(path.clone(), 0));
+
+        std::fs::write(&path, b"12345").unwrap();
+        assert_eq!(resolve(&dir, ExistingFilePolicy::Skip, 10).await, DownloadTarget::Skip(path.clone()));
+        assert_eq!(resolve(&dir, ExistingFilePolicy::Overwrite, 10).await, DownloadTarget::Download(path.clone(), 0));
+        assert_eq!(resolve(&dir, ExistingFilePolicy::Rename, 10).await,
+                   DownloadTarget::Download(path.with_file_name("file_1.mkv"), 0));
+        assert_eq!(resolve(&dir, ExistingFilePolicy::Resume, 10).await, DownloadTarget::Download(path.clone(), 5));
+        assert_eq!(resolve(&dir, ExistingFilePolicy::Resume, 5).await, DownloadTarget::Skip(path.clone()));
+        // a larger file is not the offered one
+        assert_eq!(resolve(&dir, ExistingFilePolicy::Resume, 3).await,
+                   DownloadTarget::Download(path.with_file_name("file_1.mkv"), 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finds_free_names() {
+        let dir = test_dir("rename");
+        let paths = paths(&dir, "{filename}", ExistingFilePolicy::Rename);
+        for name in ["file.mkv", "file_1.mkv", "file_2.mkv", "README", "README_1"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        assert_eq!(paths.free_path(&dir.join("file.mkv")).await, dir.join("file_3.mkv"));
+        assert_eq!(paths.free_path(&dir.join("README")).await, dir.join("README_2"));
+        assert_eq!(paths.free_path(&dir.join("archive.tar.gz")).await, dir.join("archive.tar_1.gz"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::pack_info::PackInfo;
+use crate::summary::PackResult;
+
+/// Events emitted while running a command, used by the front-ends to report progress
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connected { server: String },
+    Joined { channel: String },
+    // The packs were requested from the bot
+    Requested { bot: String, packs: Vec<u32> },
+    Offer { bot: String, filename: String, size: u64, ip: IpAddr, port: u16 },
+    // The bot put us in its queue, `total` is the length of the queue if the bot told us
+    Queued { bot: String, pack: Option<u32>, position: u32, total: Option<u32> },
+    // The pack failed and will be requested again after the delay
+    Retrying { bot: String, pack: Option<u32>, retry: u32, delay_seconds: f64 },
+    // The connection to the network was closed, connecting again after the delay
+    Reconnecting { attempt: u32, delay_seconds: f64 },
+    // The pack failed and all retries are used up
+    PackFailed { bot: String, pack: Option<u32>, reason: String },
+    // The reply to `xdcc info`
+    Info(PackInfo),
+    // The outcome of every pack, sent once all packs are done
+    Summary { results: Vec<PackResult> },
+    #[serde(untagged)]
+    Transfer(TransferEvent),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TransferEvent {
+    // The sender connected, `position` is larger than zero for resumed downloads
+    Started { id: usize, path: PathBuf, file_size: u64, position: u64 },
+    // Total number of bytes of the file we received so far, including resumed bytes
+    Progress { id: usize, received: u64 },
+    // `bytes` were received in this session, `md5` is the checksum of the whole file
+    Finished { id: usize, path: PathBuf, bytes: u64, duration_seconds: f64, md5: String },
+    // The file already exists and was not downloaded
+    Skipped { id: usize, path: PathBuf },
+    Failed { id: usize, reason: String },
+}
+
+/// Reports the events of a single transfer
+#[derive(Debug, Clone)]
+pub struct TransferMonitor {
+    id: usize,
+    sender: UnboundedSender<Event>,
+}
+
+impl TransferMonitor {
+    pub fn new(id: usize, sender: UnboundedSender<Event>) -> Self {
+        Self { id, sender }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    fn send(&self, event: TransferEvent) {
+        // nobody may be listening anymore, e.g. while shutting down
+        let _ = self.sender.send(Event::Transfer(event));
+    }
+
+    pub fn started(&self, path: PathBuf, file_size: u64, position: u64) {
+        self.send(TransferEvent::Started { id: self.id, path, file_size, position });
+    }
+
+    pub fn progress(&self, received: u64) {
+        self.send(TransferEvent::Progress { id: self.id, received });
+    }
+
+    pub fn finished(&self, path: PathBuf, bytes: u64, duration_seconds: f64, md5: String) {
+        self.send(TransferEvent::Finished { id: self.id, path, bytes, duration_seconds, md5 });
+    }
+
+    pub fn skipped(&self, path: PathBuf) {
+        self.send(TransferEvent::Skipped { id: self.id, path });
+    }
+
+    pub fn failed(&self, reason: String) {
+        self.send(TransferEvent::Failed { id: self.id, reason });
+    }
+}
@@ -0,0 +1,105 @@
+const MAX_FILENAME_BYTES: usize = 255;
+const FALLBACK_FILENAME: &str = "unnamed";
+const RESERVED_CHARACTERS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+// Names which cannot be used as files on Windows, regardless of the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters which change how text is displayed without being visible,
+/// e.g. to disguise `exe.mkv` as `vkm.exe`
+fn is_invisible_formatting(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+fn truncate(filename: String) -> String {
+    if filename.len() <= MAX_FILENAME_BYTES {
+        return filename;
+    }
+    // keep the extension, if it is reasonably short
+    let (stem, extension) = match filename.rfind('.') {
+        Some(index) if filename.len() - index <= 16 => filename.split_at(index),
+        _ => (filename.as_str(), ""),
+    };
+    let mut length = MAX_FILENAME_BYTES - extension.len();
+    while !stem.is_char_boundary(length) {
+        length -= 1;
+    }
+    format!("{}{}", &stem[..length], extension)
+}
+
+/// Turns a filename sent by an untrusted bot into a safe name for a single file
+/// in the output directory.
+pub fn sanitize_filename(filename: &str) -> String {
+    // only keep the last path component
+    let name = filename.rsplit(['/', '\\']).find(|part| !part.is_empty()).unwrap_or("");
+    let name: String = name.chars()
+        .filter(|c| !c.is_control() && !is_invisible_formatting(*c))
+        .collect();
+    // no hidden files, and Windows does not allow trailing dots
+    let name = name.trim_start_matches(|c: char| c == '.' || c.is_whitespace())
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace());
+    let mut name: String = name.chars()
+        .map(|c| if c.is_whitespace() || RESERVED_CHARACTERS.contains(&c) { '_' } else { c })
+        .collect();
+
+    let stem = name.split('.').next().unwrap_or("");
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        name.insert(0, '_');
+    }
+    let mut name = truncate(name);
+    if name.is_empty() {
+        name = FALLBACK_FILENAME.to_string();
+    }
+
+    if name != filename.replace(' ', "_") {
+        log::warn!("Sanitized filename {:?} to {:?}.", filename, name);
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_path_components() {
+        assert_eq!(sanitize_filename("../../.bashrc"), "bashrc");
+        assert_eq!(sanitize_filename("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("..\\..\\Windows\\evil.exe"), "evil.exe");
+        assert_eq!(sanitize_filename("C:\\Users\\me\\file.mkv"), "file.mkv");
+    }
+
+    #[test]
+    fn removes_control_and_formatting_characters() {
+        assert_eq!(sanitize_filename("file\x07\r\n.mkv"), "file.mkv");
+        assert_eq!(sanitize_filename("show\u{202E}vkm.exe"), "showvkm.exe");
+        assert_eq!(sanitize_filename("a\u{200B}b\u{2066}c\u{FEFF}.txt"), "abc.txt");
+    }
+
+    #[test]
+    fn escapes_reserved_names() {
+        assert_eq!(sanitize_filename("CON.txt"), "_CON.txt");
+        assert_eq!(sanitize_filename("lpt1"), "_lpt1");
+    }
+
+    #[test]
+    fn replaces_empty_names() {
+        assert_eq!(sanitize_filename("...."), FALLBACK_FILENAME);
+        assert_eq!(sanitize_filename(". . ."), FALLBACK_FILENAME);
+        assert_eq!(sanitize_filename("../"), FALLBACK_FILENAME);
+    }
+
+    #[test]
+    fn shortens_long_names_on_character_boundaries() {
+        // three bytes per character, so the limit falls inside a character
+        let filename = format!("{}.mkv", "日".repeat(100));
+        let sanitized = sanitize_filename(&filename);
+        assert!(std::str::from_utf8(sanitized.as_bytes()).is_ok());
+        assert!(sanitized.len() <= MAX_FILENAME_BYTES);
+        assert!(sanitized.ends_with(".mkv"));
+        assert!(sanitized.starts_with("日日"));
+    }
+}
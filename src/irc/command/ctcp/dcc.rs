@@ -1,15 +1,33 @@
-use std::net::Ipv4Addr;
+use std::io::SeekFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use lazy_static::lazy_static;
-use regex::Regex;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
-use tokio::net::TcpStream;
+use md5::{Digest, Md5};
+use regex::{Captures, Regex};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::event::TransferMonitor;
+use crate::filename::sanitize_filename;
+use crate::irc::command::MessageTo;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
 
 lazy_static! {
+    // passive offers always use port 0 and append a token
+    static ref CTCP_DCC_PASSIVE_SEND_REGEX: Regex =
+        Regex::new(r#"\x01DCC SEND "?([^"]*)"? (\S+) 0 (\d+) (\d+)\x01"#).unwrap();
     static ref CTCP_DCC_SEND_REGEX: Regex =
-        Regex::new(r#"\x01DCC SEND "?([^"]*)"? (\d+) (\d+) (\d+)\x01"#).unwrap();
+        Regex::new(r#"\x01DCC SEND "?([^"]*)"? (\S+) (\d+) (\d+)\x01"#).unwrap();
+    static ref CTCP_DCC_ACCEPT_REGEX: Regex =
+        Regex::new(r#"\x01DCC ACCEPT "?([^"]*)"? (\d+) (\d+)(?: (\d+))?\x01"#).unwrap();
 }
 
 #[derive(Debug)]
@@ -20,53 +38,581 @@ pub struct Dcc {
 
 #[derive(Debug)]
 pub enum DccType {
-    Send(Send)
+    Send(Send),
+    Accept(Accept),
 }
 
 #[derive(Debug)]
 pub struct Send {
     pub filename: String,
-    ip: Ipv4Addr,
+    ip: IpAddr,
     port: u16,
-    file_size: usize,
+    file_size: u64,
+    token: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct Accept {
+    pub filename: String,
+    pub port: u16,
+    pub position: u64,
+    pub token: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct Resume {
+    pub recipient: String,
+    pub filename: String,
+    pub port: u16,
+    pub position: u64,
+    pub token: Option<u32>,
+}
+
+/// Our answer to a passive DCC SEND, telling the sender where to connect to
+#[derive(Debug)]
+pub struct PassiveSend {
+    pub recipient: String,
+    pub filename: String,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub file_size: u64,
+    pub token: u32,
+}
+
+/// How received bytes are acknowledged to the sender
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum AckMode {
+    // Acknowledge every received block with the 32-bit byte count modulo 2^32
+    Full,
+    // Acknowledge every received block with a 64-bit byte count
+    Full64,
+    // Do not send acknowledgements, for senders using "turbo" DCC
+    Turbo,
+}
+
+impl AckMode {
+    fn encode(&self, progress: u64) -> Option<Vec<u8>> {
+        match self {
+            AckMode::Full => Some((progress as u32).to_be_bytes().to_vec()),
+            AckMode::Full64 => Some(progress.to_be_bytes().to_vec()),
+            AckMode::Turbo => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    pub ack_mode: AckMode,
+}
+
+/// Settings for receiving passive (reverse) DCC transfers
+#[derive(Debug, Clone)]
+pub struct PassiveDcc {
+    // IP address advertised to the sender
+    pub ip: IpAddr,
+    // Ports to listen on for incoming connections, any free port if not set
+    pub ports: Option<RangeInclusive<u16>>,
+    pub accept_timeout: Duration,
+}
+
+impl PassiveDcc {
+    pub async fn bind(&self) -> Result<TcpListener> {
+        let address = match self.ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let ports = match &self.ports {
+            Some(ports) => ports.clone(),
+            None => return Ok(TcpListener::bind((address, 0)).await?),
+        };
+        for port in ports.clone() {
+            if let Ok(listener) = TcpListener::bind((address, port)).await {
+                return Ok(listener);
+            }
+        }
+        bail!("No free port in range {}-{} for passive DCC", ports.start(), ports.end())
+    }
+}
+
+/// IPv4 addresses are sent as a decimal integer, IPv6 addresses in their textual form
+fn parse_ip(captures: &Captures, index: usize) -> Result<IpAddr> {
+    let value = &captures[index];
+    if value.bytes().all(|b| b.is_ascii_digit()) {
+        Ok(IpAddr::V4(Ipv4Addr::from(parse_field::<u32>(captures, index, "IP address")?)))
+    } else {
+        value.parse::<IpAddr>().map_err(|_| anyhow!("DCC IP address {} is invalid", value))
+    }
+}
+
+/// The IPv4 address of IPv4-mapped (::ffff:0:0/96), NAT64 (64:ff9b::/96) and 6to4 (2002::/16) addresses,
+/// which reach that IPv4 address
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] | [0x2002, high, low, ..] => {
+            Some(Ipv4Addr::from(((high as u32) << 16) | low as u32))
+        }
+        _ => ip.to_ipv4_mapped(),
+    }
 }
 
-impl<'a> TryFrom<&'a str> for DccType {
-    type Error = &'a str;
+/// Addresses which are not reachable over the internet, connecting to them
+/// would let a sender reach services in our own network
+fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // 100.64.0.0/10 is used for carrier-grade NAT
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+                || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_private_ip(IpAddr::V4(ip)),
+            // unique local (fc00::/7) and link local (fe80::/10) addresses
+            None => ip.is_loopback() || ip.is_unspecified() || ip.segments()[0] & 0xfe00 == 0xfc00
+                || ip.segments()[0] & 0xffc0 == 0xfe80,
+        },
+    }
+}
+
+fn format_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip).to_string(),
+        IpAddr::V6(ip) => ip.to_string(),
+    }
+}
+
+fn parse_field<T: FromStr>(captures: &Captures, index: usize, name: &str) -> Result<T> {
+    let value = &captures[index];
+    value.parse::<T>().map_err(|_| anyhow!("DCC {} {} is out of range", name, value))
+}
+
+impl TryFrom<&str> for DccType {
+    type Error = anyhow::Error;
 
-    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
-        if let Some(captures) = CTCP_DCC_SEND_REGEX.captures(value) {
-            let ip_number = captures[2].parse::<u32>().unwrap();
+    fn try_from(value: &str) -> Result<Self> {
+        if let Some(captures) = CTCP_DCC_PASSIVE_SEND_REGEX.captures(value) {
             let send = Send {
                 filename: captures[1].to_string(),
-                ip: Ipv4Addr::from(ip_number),
-                port: captures[3].parse::<u16>().unwrap(),
-                file_size: captures[4].parse::<usize>().unwrap(),
+                ip: parse_ip(&captures, 2)?,
+                port: 0,
+                file_size: parse_field(&captures, 3, "file size")?,
+                token: Some(parse_field(&captures, 4, "token")?),
             };
             Ok(Self::Send(send))
+        } else if let Some(captures) = CTCP_DCC_SEND_REGEX.captures(value) {
+            let send = Send {
+                filename: captures[1].to_string(),
+                ip: parse_ip(&captures, 2)?,
+                port: parse_field(&captures, 3, "port")?,
+                file_size: parse_field(&captures, 4, "file size")?,
+                token: None,
+            };
+            Ok(Self::Send(send))
+        } else if let Some(captures) = CTCP_DCC_ACCEPT_REGEX.captures(value) {
+            let token = match captures.get(4) {
+                Some(_) => Some(parse_field(&captures, 4, "token")?),
+                None => None,
+            };
+            let accept = Accept {
+                filename: captures[1].to_string(),
+                port: parse_field(&captures, 2, "port")?,
+                position: parse_field(&captures, 3, "position")?,
+                token,
+            };
+            Ok(Self::Accept(accept))
         } else {
-            Err(value)
+            bail!("Unsupported DCC request: {}", value.trim_matches('\x01'))
+        }
+    }
+}
+
+/// Feeds the already downloaded part of a resumed file into the checksum
+async fn hash_prefix(file: &mut File, length: u64, hasher: &mut Md5) -> Result<()> {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk = remaining.min(buffer.len() as u64) as usize;
+        let count = file.read(&mut buffer[..chunk]).await?;
+        if count == 0 {
+            bail!("File is shorter than the resume position {}", length);
+        }
+        hasher.update(&buffer[..count]);
+        remaining -= count as u64;
+    }
+    Ok(())
+}
+
+/// Opens the file to download to, continuing an existing partial file if `position` is not zero.
+/// This happens before connecting, senders drop idle connections while a large prefix is hashed.
+async fn open_file(path: &Path, position: u64) -> Result<(File, Md5)> {
+    let mut hasher = Md5::new();
+    if position == 0 {
+        return Ok((File::create(path).await?, hasher));
+    }
+    log::info!("Resuming download of {} at byte {}.", path.display(), position);
+    let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+    hash_prefix(&mut file, position, &mut hasher).await?;
+    file.set_len(position).await?;
+    file.seek(SeekFrom::Start(position)).await?;
+    Ok((file, hasher))
+}
+
+fn quote_filename(filename: String) -> String {
+    if filename.contains(' ') {
+        format!("\"{}\"", filename)
+    } else {
+        filename
+    }
+}
+
+impl From<Resume> for MessageTo {
+    fn from(value: Resume) -> Self {
+        let filename = quote_filename(value.filename);
+        let message = match value.token {
+            Some(token) => format!("\x01DCC RESUME {} {} {} {}\x01", filename, value.port, value.position, token),
+            None => format!("\x01DCC RESUME {} {} {}\x01", filename, value.port, value.position),
+        };
+        MessageTo {
+            recipient: value.recipient,
+            message,
+        }
+    }
+}
+
+impl From<PassiveSend> for MessageTo {
+    fn from(value: PassiveSend) -> Self {
+        MessageTo {
+            recipient: value.recipient,
+            message: format!("\x01DCC SEND {} {} {} {} {}\x01", quote_filename(value.filename),
+                             format_ip(value.ip), value.port, value.file_size, value.token),
         }
     }
 }
 
 impl Send {
+    /// The filename sent by the bot, made safe to use as a local filename
     pub fn normalized_filename(&self) -> String {
-        self.filename.replace(" ", "_")
+        sanitize_filename(&self.filename)
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn token(&self) -> Option<u32> {
+        self.token
+    }
+
+    /// Passive offers expect us to listen and the sender to connect
+    pub fn is_passive(&self) -> bool {
+        self.port == 0 && self.token.is_some()
     }
 
-    pub async fn start_download(&self) -> Result<()> {
-        let mut file = BufWriter::new(File::create(self.normalized_filename()).await?);
-        let mut stream = TcpStream::connect((self.ip, self.port)).await?;
+    /// Whether accepting the offer would connect us to a private or loopback address
+    pub fn has_private_endpoint(&self) -> bool {
+        !self.is_passive() && is_private_ip(self.ip)
+    }
+
+    pub fn passive_reply(&self, recipient: String, passive: &PassiveDcc, port: u16) -> Option<PassiveSend> {
+        Some(PassiveSend {
+            recipient,
+            filename: self.filename.clone(),
+            ip: passive.ip,
+            port,
+            file_size: self.file_size,
+            token: self.token?,
+        })
+    }
+
+    /// Downloads the file by connecting to the sender
+    pub async fn start_download(&self, path: &Path, position: u64, options: &TransferOptions,
+                                monitor: &TransferMonitor) -> Result<()> {
+        let target = open_file(path, position).await?;
+        let stream = TcpStream::connect((self.ip, self.port)).await?;
+        self.download(stream, target, path, position, options, monitor).await
+    }
+
+    /// Downloads the file by waiting for the sender to connect to our listener
+    pub async fn start_passive_download(&self, listener: TcpListener, path: &Path, position: u64,
+                                        accept_timeout: Duration, options: &TransferOptions,
+                                        monitor: &TransferMonitor) -> Result<()> {
+        let target = open_file(path, position).await?;
+        let stream = self.accept(listener, accept_timeout).await?;
+        self.download(stream, target, path, position, options, monitor).await
+    }
+
+    /// Receives a small file, e.g. a pack list, into memory by connecting to the sender
+    pub async fn fetch(&self, options: &TransferOptions) -> Result<Vec<u8>> {
+        let stream = TcpStream::connect((self.ip, self.port)).await?;
+        self.receive(stream, options).await
+    }
+
+    /// Receives a small file into memory by waiting for the sender to connect to our listener
+    pub async fn fetch_passive(&self, listener: TcpListener, accept_timeout: Duration,
+                               options: &TransferOptions) -> Result<Vec<u8>> {
+        let stream = self.accept(listener, accept_timeout).await?;
+        self.receive(stream, options).await
+    }
+
+    async fn accept(&self, listener: TcpListener, accept_timeout: Duration) -> Result<TcpStream> {
+        let (stream, address) = match timeout(accept_timeout, listener.accept()).await {
+            Ok(result) => result?,
+            Err(_) => bail!("Timed out waiting for passive DCC connection for {}", self.filename),
+        };
+        log::info!("Accepted passive DCC connection from {} for {}.", address, self.filename);
+        Ok(stream)
+    }
+
+    async fn receive(&self, mut stream: TcpStream, options: &TransferOptions) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.file_size as usize);
         let mut buffer = [0; 4096];
-        let mut progress: usize = 0;
+        while (data.len() as u64) < self.file_size {
+            let length = (self.file_size - data.len() as u64).min(buffer.len() as u64) as usize;
+            let count = stream.read(&mut buffer[..length]).await?;
+            if count == 0 {
+                bail!("Transfer truncated at {} of {} bytes", data.len(), self.file_size);
+            }
+            data.extend_from_slice(&buffer[..count]);
+            if let Some(ack) = options.ack_mode.encode(data.len() as u64) {
+                if let Err(e) = stream.write_all(&ack).await {
+                    log::debug!("Failed to acknowledge {} bytes of {}: {}", data.len(), self.filename, e);
+                }
+            }
+        }
+        stream.shutdown().await?;
+        Ok(data)
+    }
+
+    /// Receives the file into `file`, which already contains the first `position` bytes
+    async fn download(&self, mut stream: TcpStream, (file, mut hasher): (File, Md5), path: &Path, position: u64,
+                      options: &TransferOptions, monitor: &TransferMonitor) -> Result<()> {
+        let started_at = Instant::now();
+        let mut file = BufWriter::new(file);
+        let mut buffer = [0; 4096];
+        let mut progress: u64 = position;
+        let mut last_report = Instant::now();
+        monitor.started(path.to_path_buf(), self.file_size, position);
         while progress < self.file_size {
-            let count = stream.read(&mut buffer[..]).await?;
-            file.write_all(&mut buffer[..count]).await?;
-            progress += count;
+            // never read past the announced size, any additional data is not part of the file
+            let length = (self.file_size - progress).min(buffer.len() as u64) as usize;
+            let count = stream.read(&mut buffer[..length]).await?;
+            if count == 0 {
+                // keep what we received, so the download can be resumed
+                file.flush().await?;
+                bail!("Transfer truncated at {} of {} bytes", progress, self.file_size);
+            }
+            file.write_all(&buffer[..count]).await?;
+            hasher.update(&buffer[..count]);
+            progress += count as u64;
+            if let Some(ack) = options.ack_mode.encode(progress) {
+                // the sender may already have closed the connection, this is caught by the next read
+                if let Err(e) = stream.write_all(&ack).await {
+                    log::debug!("Failed to acknowledge {} bytes of {}: {}", progress, self.filename, e);
+                }
+            }
+            if last_report.elapsed() >= PROGRESS_INTERVAL {
+                monitor.progress(progress);
+                last_report = Instant::now();
+            }
         }
         file.flush().await?;
+        if let Ok(count) = stream.try_read(&mut buffer) {
+            if count > 0 {
+                log::warn!("{} sent more than the announced {} bytes, ignoring the rest.",
+                    self.filename, self.file_size);
+            }
+        }
         stream.shutdown().await?;
+        monitor.progress(progress);
+        let md5 = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        monitor.finished(path.to_path_buf(), progress - position, started_at.elapsed().as_secs_f64(), md5);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::task::JoinHandle;
+
+    use crate::event::{Event, TransferEvent};
+
+    /// A local DCC sender which sends the payload and returns the acknowledgements it received
+    async fn fake_sender(payload: Vec<u8>) -> (u16, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(&payload).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut acks = Vec::new();
+            // the receiver may reset the connection if it did not read everything
+            let _ = stream.read_to_end(&mut acks).await;
+            acks
+        });
+        (port, handle)
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("xdcc-cli-test-{}-{}", std::process::id(), name))
+    }
+
+    /// Downloads the payload from a fake sender announcing `file_size` bytes
+    async fn download(name: &str, payload: Vec<u8>, file_size: u64, ack_mode: AckMode)
+                      -> (Result<()>, Vec<u8>, Vec<u8>) {
+        let (port, sender) = fake_sender(payload).await;
+        let send = Send {
+            filename: name.to_string(),
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+            file_size,
+            token: None,
+        };
+        let (events, _receiver) = unbounded_channel();
+        let monitor = TransferMonitor::new(0, events);
+        let path = test_path(name);
+        let result = send.start_download(&path, 0, &TransferOptions { ack_mode }, &monitor).await;
+        let acks = sender.await.unwrap();
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (result, file, acks)
+    }
+
+    fn payload(length: usize) -> Vec<u8> {
+        (0..length).map(|i| i as u8).collect()
+    }
+
+    fn parse_send(value: &str) -> Result<Send> {
+        match DccType::try_from(value)? {
+            DccType::Send(send) => Ok(send),
+            DccType::Accept(_) => bail!("Not a DCC SEND: {}", value),
+        }
+    }
+
+    #[test]
+    fn parses_sizes_around_4_gib() {
+        for size in [u32::MAX as u64, 1 << 32, u64::MAX] {
+            let send = parse_send(&format!("\x01DCC SEND file.mkv 3232235777 5000 {}\x01", size)).unwrap();
+            assert_eq!(send.file_size(), size);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_fields() {
+        // one more than u64::MAX
+        assert!(parse_send("\x01DCC SEND file.mkv 3232235777 5000 18446744073709551616\x01").is_err());
+        assert!(parse_send("\x01DCC SEND file.mkv 3232235777 65536 1000\x01").is_err());
+        // one more than u32::MAX
+        assert!(parse_send("\x01DCC SEND file.mkv 4294967296 5000 1000\x01").is_err());
+    }
+
+    #[test]
+    fn wraps_32_bit_acknowledgements() {
+        assert_eq!(AckMode::Full.encode(1 << 32), Some(vec![0, 0, 0, 0]));
+        assert_eq!(AckMode::Full.encode((1 << 32) + 5), Some(vec![0, 0, 0, 5]));
+        assert_eq!(AckMode::Full64.encode(1 << 32), Some(vec![0, 0, 0, 1, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn detects_private_addresses() {
+        for ip in ["10.1.2.3", "192.168.0.1", "127.0.0.1", "100.64.0.1", "::1", "fd00::1", "fe80::1",
+                   "::ffff:192.168.0.1", "64:ff9b::a01:203", "64:ff9b::7f00:1", "2002:c0a8:1::1", "2002:a00:1::"] {
+            assert!(is_private_ip(ip.parse().unwrap()), "{} is private", ip);
+        }
+        for ip in ["203.0.113.7", "2001:db8::1", "::ffff:203.0.113.7", "64:ff9b::cb00:7107", "2002:cb00:7107::1"] {
+            assert!(!is_private_ip(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn acknowledges_with_32_bit_counts() {
+        let (result, _, acks) = download("ack-full", payload(10000), 10000, AckMode::Full).await;
+        result.unwrap();
+        let counts = acks.chunks(4).map(|ack| u32::from_be_bytes(ack.try_into().unwrap())).collect::<Vec<_>>();
+        assert_eq!(acks.len() % 4, 0);
+        assert!(counts.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(counts.last(), Some(&10000));
+    }
+
+    #[tokio::test]
+    async fn acknowledges_with_64_bit_counts() {
+        let (result, _, acks) = download("ack-full64", payload(10000), 10000, AckMode::Full64).await;
+        result.unwrap();
+        let counts = acks.chunks(8).map(|ack| u64::from_be_bytes(ack.try_into().unwrap())).collect::<Vec<_>>();
+        assert_eq!(acks.len() % 8, 0);
+        assert!(counts.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(counts.last(), Some(&10000));
+    }
+
+    #[tokio::test]
+    async fn turbo_sends_no_acknowledgements() {
+        let (result, file, acks) = download("ack-turbo", payload(10000), 10000, AckMode::Turbo).await;
+        result.unwrap();
+        assert_eq!(file.len(), 10000);
+        assert!(acks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resumes_with_the_digest_of_the_whole_file() {
+        let data = payload(10000);
+        let path = test_path("resume");
+        std::fs::write(&path, &data[..4000]).unwrap();
+        let (port, sender) = fake_sender(data[4000..].to_vec()).await;
+        let send = Send {
+            filename: "resume".to_string(),
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+            file_size: 10000,
+            token: None,
+        };
+        let (events, mut receiver) = unbounded_channel();
+        let monitor = TransferMonitor::new(0, events);
+        send.start_download(&path, 4000, &TransferOptions { ack_mode: AckMode::Full }, &monitor).await.unwrap();
+        sender.await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        std::fs::remove_file(&path).unwrap();
+
+        drop(monitor);
+        let mut finished = None;
+        while let Some(event) = receiver.recv().await {
+            if let Event::Transfer(TransferEvent::Finished { bytes, md5, .. }) = event {
+                finished = Some((bytes, md5));
+            }
+        }
+        let expected = Md5::digest(&data).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(finished, Some((6000, expected)));
+    }
+
+    #[tokio::test]
+    async fn fails_on_short_transfers() {
+        let (result, file, _) = download("short", payload(6000), 10000, AckMode::Full).await;
+        assert_eq!(result.unwrap_err().to_string(), "Transfer truncated at 6000 of 10000 bytes");
+        // the partial file is kept for resuming
+        assert_eq!(file, payload(6000));
+    }
+
+    #[tokio::test]
+    async fn downloads_exact_transfers() {
+        let (result, file, _) = download("exact", payload(10000), 10000, AckMode::Full).await;
+        result.unwrap();
+        assert_eq!(file, payload(10000));
+    }
+
+    #[tokio::test]
+    async fn ignores_data_past_the_announced_size() {
+        let (result, file, _) = download("overlong", payload(12000), 10000, AckMode::Full).await;
+        result.unwrap();
+        assert_eq!(file.len(), 10000);
+        assert_eq!(file, payload(10000));
+    }
+}
@@ -65,17 +65,23 @@ impl<'a> TryFrom<&'a str> for CtcpRequestType {
     type Error = &'a str;
 
     fn try_from(value: &'a str) -> Result<Self, Self::Error> {
-        if CTCP_VERSION_REGEX.is_match(&value) {
+        if CTCP_VERSION_REGEX.is_match(value) {
             Ok(Self::Version)
-        } else if let Some(captures) = CTCP_PING_REGEX.captures(&value) {
+        } else if let Some(captures) = CTCP_PING_REGEX.captures(value) {
             let content = captures.get(1).unwrap().as_str();
             Ok(Self::Ping(content.to_owned()))
-        } else if CTCP_TIME_REGEX.is_match(&value) {
+        } else if CTCP_TIME_REGEX.is_match(value) {
             Ok(Self::Time)
-        } else if CTCP_CLIENTINFO_REGEX.is_match(&value) {
+        } else if CTCP_CLIENTINFO_REGEX.is_match(value) {
             Ok(Self::ClientInfo)
-        } else if let Ok(dcc_type) = value.try_into() {
-            Ok(Self::Dcc(dcc_type))
+        } else if value.starts_with("\x01DCC ") {
+            match DccType::try_from(value) {
+                Ok(dcc_type) => Ok(Self::Dcc(dcc_type)),
+                Err(e) => {
+                    log::warn!("Ignoring invalid DCC request: {}", e);
+                    Err(value)
+                }
+            }
         } else {
             Err(value)
         }
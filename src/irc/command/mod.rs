@@ -6,13 +6,28 @@ pub mod xdcc;
 
 
 lazy_static! {
-    static ref PRIVMSG_REGEX: Regex = Regex::new(r#":([^!]*)!.* PRIVMSG .* :(.*)"#).unwrap();
+    static ref PRIVMSG_REGEX: Regex = Regex::new(r#":([^!]*)!\S* PRIVMSG (\S+) :(.*)"#).unwrap();
+    static ref NOTICE_REGEX: Regex = Regex::new(r#":([^!]*)!\S* NOTICE (\S+) :(.*)"#).unwrap();
     static ref PING_REGEX: Regex = Regex::new(r#"PING (.*)"#).unwrap();
+    // RPL_ENDOFNAMES, the last reply to joining a channel
+    static ref END_OF_NAMES_REGEX: Regex = Regex::new(r#"^:\S+ 366 \S+ (\S+)"#).unwrap();
+    // ERR_NICKNAMEINUSE
+    static ref NICKNAME_IN_USE_REGEX: Regex = Regex::new(r#"^:\S+ 433 \S+ (\S+)"#).unwrap();
+    // RPL_LOGGEDIN, sent by networks which support account notifications
+    static ref LOGGED_IN_REGEX: Regex = Regex::new(r#"^:\S+ 900 \S+ \S+ (\S+)"#).unwrap();
+    // e.g. Anope `Password accepted - you are now recognized.` or Atheme `You are now identified for account.`
+    static ref IDENTIFIED_REGEX: Regex =
+        Regex::new(r#"(?i)password accepted|you are now (identified|recognized|logged in)"#).unwrap();
+    static ref IDENTIFY_FAILED_REGEX: Regex =
+        Regex::new(r#"(?i)(password (incorrect|invalid)|(invalid|incorrect) password|is ?n[o']t registered)"#)
+            .unwrap();
 }
 
 #[derive(Debug)]
 pub(crate) struct MessageFrom {
     pub(crate) sender: String,
+    // our nickname or a channel
+    pub(crate) target: String,
     pub(crate) message: String,
 }
 
@@ -25,7 +40,14 @@ pub(crate) struct MessageTo {
 #[derive(Debug)]
 pub(crate) enum ServerCommand {
     PrivMsg(MessageFrom),
+    Notice(MessageFrom),
     Ping(String),
+    // The server sent the names of the channel, so we joined it
+    EndOfNames(String),
+    // Another user has the nickname we asked for
+    NicknameInUse(String),
+    // We are logged in to the account
+    LoggedIn(String),
     #[allow(dead_code)]
     Unknown(String),
 }
@@ -46,10 +68,23 @@ impl From<&str> for ServerCommand {
     fn from(value: &str) -> Self {
         if let Some(captures) = PING_REGEX.captures(value) {
             Self::Ping(captures.get(1).unwrap().as_str().to_string())
+        } else if let Some(captures) = END_OF_NAMES_REGEX.captures(value) {
+            Self::EndOfNames(captures.get(1).unwrap().as_str().to_string())
+        } else if let Some(captures) = NICKNAME_IN_USE_REGEX.captures(value) {
+            Self::NicknameInUse(captures.get(1).unwrap().as_str().to_string())
+        } else if let Some(captures) = LOGGED_IN_REGEX.captures(value) {
+            Self::LoggedIn(captures.get(1).unwrap().as_str().to_string())
         } else if let Some(captures) = PRIVMSG_REGEX.captures(value) {
             Self::PrivMsg(MessageFrom {
                 sender: captures.get(1).unwrap().as_str().to_string(),
-                message: captures.get(2).unwrap().as_str().to_string(),
+                target: captures.get(2).unwrap().as_str().to_string(),
+                message: captures.get(3).unwrap().as_str().to_string(),
+            })
+        } else if let Some(captures) = NOTICE_REGEX.captures(value) {
+            Self::Notice(MessageFrom {
+                sender: captures.get(1).unwrap().as_str().to_string(),
+                target: captures.get(2).unwrap().as_str().to_string(),
+                message: captures.get(3).unwrap().as_str().to_string(),
             })
         } else {
             Self::Unknown(value.to_string())
@@ -57,6 +92,39 @@ impl From<&str> for ServerCommand {
     }
 }
 
+/// Replies of NickServ to IDENTIFY
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum NickServReply {
+    Identified,
+    Rejected,
+}
+
+impl TryFrom<&str> for NickServReply {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if IDENTIFIED_REGEX.is_match(value) {
+            Ok(Self::Identified)
+        } else if IDENTIFY_FAILED_REGEX.is_match(value) {
+            Ok(Self::Rejected)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl ClientCommand {
+    /// The command as it is logged, without the credentials of messages to NickServ
+    pub(crate) fn redacted(&self) -> String {
+        match self {
+            ClientCommand::PrivMsg(message) if message.recipient.eq_ignore_ascii_case("NickServ") => {
+                format!("PRIVMSG {} :<redacted>", message.recipient)
+            }
+            command => String::from(command).trim().to_string(),
+        }
+    }
+}
+
 impl From<&ClientCommand> for String {
     fn from(value: &ClientCommand) -> Self {
         match value {
@@ -70,3 +138,40 @@ impl From<&ClientCommand> for String {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_numeric_replies() {
+        let command = ServerCommand::from(":irc.rizon.io 366 xdcc-cli #nibl :End of /NAMES list.");
+        assert!(matches!(command, ServerCommand::EndOfNames(channel) if channel == "#nibl"));
+        let command = ServerCommand::from(":irc.rizon.io 433 * xdcc-cli :Nickname is already in use.");
+        assert!(matches!(command, ServerCommand::NicknameInUse(nickname) if nickname == "xdcc-cli"));
+        let command = ServerCommand::from(
+            ":irc.libera.chat 900 xdcc-cli xdcc-cli!user@host account :You are now logged in as account");
+        assert!(matches!(command, ServerCommand::LoggedIn(account) if account == "account"));
+        let command = ServerCommand::from(":bot!bot@host PRIVMSG xdcc-cli :433 * xdcc-cli");
+        assert!(matches!(command, ServerCommand::PrivMsg(message) if message.target == "xdcc-cli"));
+    }
+
+    #[test]
+    fn classifies_nickserv_replies() {
+        let replies = [
+            // Anope
+            ("Password accepted - you are now recognized.", Ok(NickServReply::Identified)),
+            ("Password incorrect.", Ok(NickServReply::Rejected)),
+            ("Nick xdcc-cli isn't registered.", Ok(NickServReply::Rejected)),
+            // Atheme
+            ("You are now identified for \x02account\x02.", Ok(NickServReply::Identified)),
+            ("Invalid password for \x02account\x02.", Ok(NickServReply::Rejected)),
+            ("\x02xdcc-cli\x02 is not registered.", Ok(NickServReply::Rejected)),
+            ("This nickname is registered. Please choose a different nickname, or identify via /msg NickServ \
+              IDENTIFY <password>.", Err(())),
+        ];
+        for (message, expected) in replies {
+            assert_eq!(NickServReply::try_from(message), expected, "{}", message);
+        }
+    }
+}
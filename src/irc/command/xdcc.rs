@@ -1,3 +1,6 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, bail, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
 
@@ -9,9 +12,29 @@ lazy_static! {
     static ref XDCC_CANCEL_REGEX: Regex = Regex::new(r#"/msg (.*) xdcc cancel"#).unwrap();
     static ref XDCC_INFO_REGEX: Regex = Regex::new(r#"/msg (.*) xdcc info #?(\d+)"#).unwrap();
     static ref XDCC_SEND_REGEX: Regex = Regex::new(r#"/msg (.*) xdcc (send|get) #?(-1|\d+|list)"#).unwrap();
-    static ref XDCC_BATCH_REGEX: Regex = Regex::new(r#"/msg (.*) xdcc batch ((#?\d+,)*#?\d+)"#).unwrap();
+    static ref XDCC_BATCH_REGEX: Regex =
+        Regex::new(r#"/msg (.*) xdcc batch (#?\d+(?:-#?\d+)?(?:\s*,\s*#?\d+(?:-#?\d+)?)*)"#).unwrap();
+    static ref SENDING_REGEX: Regex = Regex::new(r#"(?i)sending you pack #?(\d+) \("(.*?)"\)"#).unwrap();
+    static ref INVALID_PACK_REGEX: Regex = Regex::new(r#"(?i)invalid pack number"#).unwrap();
+    static ref ALREADY_REQUESTED_REGEX: Regex = Regex::new(r#"(?i)already (requested|have) that"#).unwrap();
+    static ref QUEUED_REGEX: Regex = Regex::new(r#"(?i)queue.*\bposition (\d+)(?: of (\d+))?"#).unwrap();
+    static ref QUEUED_PACK_REGEX: Regex = Regex::new(r#"(?i)queue for pack #?(\d+)"#).unwrap();
+    static ref PACK_INFO_REGEX: Regex = Regex::new(r#"(?i)pack info for pack #?(\d+)"#).unwrap();
+    static ref INFO_FIELD_REGEX: Regex = Regex::new(
+        r#"(?i)^\s*(filename|filesize|last modified|added|gets|md5sum|crc32)\s+(.+?)\s*$"#).unwrap();
 }
 
+// Larger batches are most likely a typo
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// How batches are requested from a bot
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum BatchStyle {
+    // A single `xdcc batch` request using ranges, e.g. `1-3,7`
+    Batch,
+    // One `xdcc send` request per pack, for bots without batch support
+    Single,
+}
 
 #[derive(Debug, Clone)]
 pub enum Xdcc {
@@ -20,16 +43,34 @@ pub enum Xdcc {
     Info(String, u32),
     Remove(String, Option<u32>),
     Batch(String, Vec<u32>),
+    // Request the pack list of the bot, which is sent like a pack
+    List(String),
 }
 
 impl Xdcc {
+    /// A batch of packs without duplicates, with the same size limit as `xdcc batch` commands
+    pub fn batch(recipient: String, packages: impl IntoIterator<Item = u32>) -> Result<Self> {
+        Ok(Self::Batch(recipient, unique_packages(packages)?))
+    }
+
+    /// The requests to send for the command, batches are split into single sends for bots without batch support
+    pub fn requests(&self, style: BatchStyle) -> Vec<Xdcc> {
+        match self {
+            Xdcc::Batch(recipient, packages) if style == BatchStyle::Single => packages.iter()
+                .map(|package| Xdcc::Send(recipient.clone(), Some(*package)))
+                .collect(),
+            _ => vec![self.clone()],
+        }
+    }
+
     pub fn recipient(&self) -> &str {
         match self {
             Xdcc::Cancel(recipient) => recipient,
             Xdcc::Send(recipient, _) => recipient,
             Xdcc::Info(recipient, _) => recipient,
             Xdcc::Batch(recipient, _) => recipient,
-            Xdcc::Remove(recipient, _) => recipient
+            Xdcc::Remove(recipient, _) => recipient,
+            Xdcc::List(recipient) => recipient,
         }.as_str()
     }
 }
@@ -61,10 +102,15 @@ impl From<Xdcc> for MessageTo {
                 }
             }
             Xdcc::Batch(recipient, packages) => {
-                let packages = packages.iter().map(|p| format!("{}", p)).collect::<Vec<String>>().join(",");
                 MessageTo {
                     recipient,
-                    message: format!("xdcc batch {}", packages),
+                    message: format!("xdcc batch {}", format_batch(&packages)),
+                }
+            }
+            Xdcc::List(recipient) => {
+                MessageTo {
+                    recipient,
+                    message: "xdcc send -1".to_owned(),
                 }
             }
             Xdcc::Remove(recipient, package) => {
@@ -82,46 +128,85 @@ impl From<Xdcc> for MessageTo {
     }
 }
 
-impl<'a> TryFrom<&'a str> for Xdcc {
-    type Error = &'a str;
+/// Joins consecutive packs to ranges, e.g. `1-3,7,9-11`
+fn format_batch(packages: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &package in packages {
+        match ranges.last_mut() {
+            Some((_, end)) if end.checked_add(1) == Some(package) => *end = package,
+            _ => ranges.push((package, package)),
+        }
+    }
+    ranges.iter()
+        .map(|(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect::<Vec<_>>()
+        .join(",")
+}
 
-    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
+fn parse_package(value: &str) -> Result<u32> {
+    value.trim().trim_start_matches('#').parse::<u32>().map_err(|_| anyhow!("Invalid pack number {}", value))
+}
+
+/// Removes duplicate packs, keeping the first occurrence, and makes sure the batch is not too large
+fn unique_packages(packages: impl IntoIterator<Item = u32>) -> Result<Vec<u32>> {
+    let mut unique = Vec::new();
+    let mut seen = HashSet::new();
+    for package in packages {
+        if !seen.insert(package) {
+            continue;
+        }
+        unique.push(package);
+        if unique.len() > MAX_BATCH_SIZE {
+            bail!("Batch is larger than {} packs", MAX_BATCH_SIZE);
+        }
+    }
+    Ok(unique)
+}
+
+/// Parses packs and ranges like `1-3,7,9-11`, keeping the first occurrence of duplicates
+fn parse_batch(value: &str) -> Result<Vec<u32>> {
+    let mut ranges = Vec::new();
+    for part in value.split(',') {
+        let (start, end) = part.split_once('-').unwrap_or((part, part));
+        let (start, end) = (parse_package(start)?, parse_package(end)?);
+        if start > end {
+            bail!("Invalid pack range {}", part.trim());
+        }
+        if (end - start) as usize >= MAX_BATCH_SIZE {
+            bail!("Batch is larger than {} packs", MAX_BATCH_SIZE);
+        }
+        ranges.push(start..=end);
+    }
+    unique_packages(ranges.into_iter().flatten())
+}
+
+impl TryFrom<&str> for Xdcc {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
         let lower_value = value.to_lowercase();
         if let Some(captures) = XDCC_CANCEL_REGEX.captures(&lower_value) {
             Ok(Self::Cancel(captures.get(1).unwrap().as_str().to_string()))
         } else if let Some(captures) = XDCC_SEND_REGEX.captures(&lower_value) {
-            let package = captures.get(3).unwrap().as_str();
-            let package = if package != "list" {
-                let package = package.parse::<i32>().unwrap();
-                if package != -1 {
-                    Some(package as u32)
-                } else {
-                    None
-                }
-            } else {
-                None
-            };
-            Ok(Self::Send(
-                captures.get(1).unwrap().as_str().to_string(),
-                package,
-            ))
+            let recipient = captures.get(1).unwrap().as_str().to_string();
+            match captures.get(3).unwrap().as_str() {
+                "list" | "-1" => Ok(Self::List(recipient)),
+                package => Ok(Self::Send(recipient, Some(parse_package(package)?))),
+            }
         }  else if let Some(captures) = XDCC_INFO_REGEX.captures(&lower_value) {
-            let package = captures.get(2).unwrap().as_str().parse::<u32>().unwrap();
+            let package = parse_package(captures.get(2).unwrap().as_str())?;
             Ok(Self::Info(
                 captures.get(1).unwrap().as_str().to_string(),
                 package,
             ))
         } else if let Some(captures) = XDCC_BATCH_REGEX.captures(&lower_value) {
-            let packages = captures.get(2).unwrap().as_str()
-                .split(',')
-                .map(|s| s.trim_start_matches('#').parse::<u32>().unwrap())
-                .collect::<Vec<_>>();
+            let packages = parse_batch(captures.get(2).unwrap().as_str())?;
             Ok(Self::Batch(
                 captures.get(1).unwrap().as_str().to_string(),
                 packages,
             ))
         } else if let Some(captures) = XDCC_REMOVE_REGEX.captures(&lower_value) {
-            let package = captures.get(2).unwrap().as_str().parse::<u32>().unwrap();
+            let package = parse_package(captures.get(2).unwrap().as_str())?;
             Ok(Self::Remove(
                 captures.get(1).unwrap().as_str().to_string(),
                 Some(package),
@@ -131,8 +216,163 @@ impl<'a> TryFrom<&'a str> for Xdcc {
                 captures.get(1).unwrap().as_str().to_string(),
                 None
             ))
+        } else {
+            Err(anyhow!("{}", value))
+        }
+    }
+}
+
+/// Replies of XDCC bots, usually sent as NOTICE
+#[derive(Debug, Clone, PartialEq)]
+pub enum XdccReply {
+    // The bot starts sending the pack
+    Sending { pack: u32, filename: String },
+    // We were put in the queue of the bot, `pack` is only known for some bots
+    Queued { pack: Option<u32>, position: u32, total: Option<u32> },
+    InvalidPack,
+    // The pack is already queued or being sent to us
+    AlreadyRequested,
+    // Start of the `xdcc info` reply for a pack
+    PackInfo(u32),
+    // A line of the `xdcc info` reply, the name is lowercase
+    InfoField(String, String),
+}
+
+impl<'a> TryFrom<&'a str> for XdccReply {
+    type Error = &'a str;
+
+    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
+        if let Some(captures) = SENDING_REGEX.captures(value) {
+            Ok(Self::Sending {
+                pack: captures[1].parse().map_err(|_| value)?,
+                filename: captures[2].to_string(),
+            })
+        } else if INVALID_PACK_REGEX.is_match(value) {
+            Ok(Self::InvalidPack)
+        } else if ALREADY_REQUESTED_REGEX.is_match(value) {
+            Ok(Self::AlreadyRequested)
+        } else if let Some(captures) = QUEUED_REGEX.captures(value) {
+            Ok(Self::Queued {
+                pack: QUEUED_PACK_REGEX.captures(value).and_then(|captures| captures[1].parse().ok()),
+                position: captures[1].parse().map_err(|_| value)?,
+                total: captures.get(2).and_then(|total| total.as_str().parse().ok()),
+            })
+        } else if let Some(captures) = PACK_INFO_REGEX.captures(value) {
+            Ok(Self::PackInfo(captures[1].parse().map_err(|_| value)?))
+        } else if let Some(captures) = INFO_FIELD_REGEX.captures(value) {
+            Ok(Self::InfoField(captures[1].to_lowercase(), captures[2].to_string()))
         } else {
             Err(value)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_bot_replies() {
+        let queued = |pack, position, total| Some(XdccReply::Queued { pack, position, total });
+        let sending = |pack, filename: &str| Some(XdccReply::Sending { pack, filename: filename.to_string() });
+        let info = |name: &str, value: &str| Some(XdccReply::InfoField(name.to_string(), value.to_string()));
+        let replies = [
+            // iroffer
+            ("** Sending you pack #5 (\"[Group] Show - 01 [1080p].mkv\"), which is 1.3GB. (resume supported)",
+             sending(5, "[Group] Show - 01 [1080p].mkv")),
+            ("** All Slots Full, Added you to the main queue for pack 5 (\"show.mkv\") in position 3. \
+              To Remove yourself at a later time type \"/MSG Bot XDCC REMOVE 5\".", queued(Some(5), 3, None)),
+            ("Queued 0h12m for \"show.mkv\", in position 2 of 10. 0h5m or more remaining.", queued(None, 2, Some(10))),
+            ("** Invalid Pack Number, Try Again", Some(XdccReply::InvalidPack)),
+            ("** You already requested that pack", Some(XdccReply::AlreadyRequested)),
+            ("Pack Info for Pack #5:", Some(XdccReply::PackInfo(5))),
+            (" Filename       show.mkv", info("filename", "show.mkv")),
+            (" Filesize       1395864371 [1.3GB]", info("filesize", "1395864371 [1.3GB]")),
+            (" Last Modified  2023-05-01 12:00 UTC", info("last modified", "2023-05-01 12:00 UTC")),
+            (" Gets           42", info("gets", "42")),
+            (" md5sum         d41d8cd98f00b204e9800998ecf8427e", info("md5sum", "d41d8cd98f00b204e9800998ecf8427e")),
+            (" crc32          ABCDEF12", info("crc32", "ABCDEF12")),
+            // Ginpachi-Sensei
+            ("** Sending you pack #1 (\"[SubsPlease] Show - 01 (1080p) [ABCD1234].mkv\"), which is 1.4GB. \
+              (resume supported)", sending(1, "[SubsPlease] Show - 01 (1080p) [ABCD1234].mkv")),
+            ("Added you to the main queue for pack 12 (\"show.mkv\") in position 4. \
+              To Remove yourself at a later time type \"/MSG Ginpachi-Sensei XDCC REMOVE 12\".",
+             queued(Some(12), 4, None)),
+            ("You have a DCC pending, Set your client to receive the transfer. (You already have that pack)",
+             Some(XdccReply::AlreadyRequested)),
+            ("** Invalid Pack Number, Try Again", Some(XdccReply::InvalidPack)),
+            // anything else is not a reply we know
+            ("** XDCC SEND denied, you must be on a known channel to request a pack", None),
+            ("Welcome to #nibl!", None),
+        ];
+        for (message, expected) in replies {
+            assert_eq!(XdccReply::try_from(message).ok(), expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_packs() {
+        assert!(Xdcc::try_from("/msg bot xdcc send #99999999999").is_err());
+        assert!(Xdcc::try_from("/msg bot xdcc info #99999999999").is_err());
+        assert!(Xdcc::try_from("/msg bot xdcc remove #99999999999").is_err());
+        assert!(Xdcc::try_from("/msg bot xdcc batch 1-99999999999").is_err());
+        assert!(matches!(Xdcc::try_from("/msg bot xdcc send #4294967295"), Ok(Xdcc::Send(_, Some(u32::MAX)))));
+    }
+    #[test]
+    fn builds_batches_without_duplicates() {
+        let batch = Xdcc::batch("bot".to_string(), [3, 1, 3, 2, 1]).unwrap();
+        assert!(matches!(batch, Xdcc::Batch(_, packages) if packages == vec![3, 1, 2]));
+        assert!(Xdcc::batch("bot".to_string(), 1..=MAX_BATCH_SIZE as u32).is_ok());
+        assert!(Xdcc::batch("bot".to_string(), (1..=MAX_BATCH_SIZE as u32).chain([1])).is_ok());
+        assert!(Xdcc::batch("bot".to_string(), 0..=MAX_BATCH_SIZE as u32).is_err());
+    }
+    fn messages(command: &str, style: BatchStyle) -> Vec<String> {
+        Xdcc::try_from(command).unwrap().requests(style).into_iter()
+            .map(|request| MessageTo::from(request).message)
+            .collect()
+    }
+
+    #[test]
+    fn parses_batches() {
+        assert_eq!(parse_batch("1").unwrap(), vec![1]);
+        assert_eq!(parse_batch("1,2,3").unwrap(), vec![1, 2, 3]);
+        assert_eq!(parse_batch("10-14").unwrap(), vec![10, 11, 12, 13, 14]);
+        assert_eq!(parse_batch("#1-#3, 7 ,9-11").unwrap(), vec![1, 2, 3, 7, 9, 10, 11]);
+        assert_eq!(parse_batch("5-5").unwrap(), vec![5]);
+        // duplicates keep their first position
+        assert_eq!(parse_batch("3,1-4,2").unwrap(), vec![3, 1, 2, 4]);
+        assert!(parse_batch("5-3").is_err());
+        assert!(parse_batch("1-").is_err());
+        assert!(parse_batch("1,,2").is_err());
+        assert!(parse_batch("a-b").is_err());
+    }
+
+    #[test]
+    fn limits_the_batch_size() {
+        let max = MAX_BATCH_SIZE as u32;
+        assert_eq!(parse_batch(&format!("1-{}", max)).unwrap().len(), MAX_BATCH_SIZE);
+        assert!(parse_batch(&format!("1-{}", max + 1)).is_err());
+        assert!(parse_batch(&format!("1-{},{}", max, max + 1)).is_err());
+        // duplicates do not count
+        assert!(parse_batch(&format!("1-{},1-{}", max, max)).is_ok());
+        assert!(parse_batch("0-4294967295").is_err());
+    }
+
+    #[test]
+    fn formats_batches() {
+        assert_eq!(format_batch(&[1]), "1");
+        assert_eq!(format_batch(&[1, 2, 3, 7, 9, 10, 11]), "1-3,7,9-11");
+        assert_eq!(format_batch(&[3, 1, 2]), "3,1-2");
+        assert_eq!(format_batch(&[4294967294, 4294967295]), "4294967294-4294967295");
+        assert_eq!(format_batch(&[]), "");
+    }
+
+    #[test]
+    fn requests_batches_in_both_styles() {
+        assert_eq!(messages("/msg bot xdcc batch 1-3,7", BatchStyle::Batch), vec!["xdcc batch 1-3,7"]);
+        assert_eq!(messages("/msg bot xdcc batch 1-3,7", BatchStyle::Single),
+                   vec!["xdcc send #1", "xdcc send #2", "xdcc send #3", "xdcc send #7"]);
+        // other commands are sent as they are
+        assert_eq!(messages("/msg bot xdcc send #5", BatchStyle::Single), vec!["xdcc send #5"]);
+    }
+}
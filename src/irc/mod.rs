@@ -1,36 +1,155 @@
-use anyhow::bail;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
 use anyhow::Result;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
 
-use crate::irc::command::{ClientCommand, ServerCommand};
+use crate::irc::command::{ClientCommand, MessageFrom, MessageTo, NickServReply, ServerCommand};
 use crate::irc::command::ClientCommand::{Notice, Pong};
 use crate::irc::command::ctcp::{CtcpReply, CtcpRequest, CtcpRequestType};
-use crate::irc::command::ctcp::dcc::Dcc;
-use crate::irc::command::xdcc::Xdcc;
-use crate::irc::network::connect;
+use crate::irc::command::ctcp::dcc::{Dcc, PassiveSend, Resume};
+use crate::irc::command::xdcc::{Xdcc, XdccReply};
+use crate::irc::network::{connect, ServerAddress};
+use crate::irc::tls::TlsOptions;
 
-mod network;
+pub(crate) mod network;
 pub mod command;
+pub mod tls;
+
+// Servers which do not confirm joining a channel should not keep us waiting forever
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+// NickServ may not answer at all, e.g. on networks without services
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+// How many underscores to append to a nickname which is already in use
+const MAX_NICKNAME_SUFFIX: usize = 5;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpPreference {
+    Any,
+    V4,
+    V6,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub server: String,
+    pub tls: Option<TlsOptions>,
+    pub ip_preference: IpPreference,
+}
+
+/// Credentials to identify with NickServ after logging in
+#[derive(Debug, Clone)]
+pub struct NickServ {
+    pub account: Option<String>,
+    pub password: String,
+}
+
+/// Messages from bots which are relevant for downloading packs
+#[derive(Debug)]
+pub enum BotMessage {
+    Dcc(Dcc),
+    Xdcc { sender: String, reply: XdccReply },
+}
 
 pub struct IrcDccClient {
     client_command_sender: UnboundedSender<ClientCommand>,
     server_command_receiver: UnboundedReceiver<ServerCommand>,
+    // the session, to restore it when reconnecting
+    servers: Vec<ConnectOptions>,
+    // index of the server we are connected to, reconnecting tries the next one
+    current_server: usize,
+    // the nickname passed to login, tried first when reconnecting
+    requested_nickname: Option<String>,
+    // the nickname accepted by the server
+    nickname: Option<String>,
+    nickserv: Option<NickServ>,
+    channels: Vec<String>,
+    // the server sends a CTCP VERSION once per connection, which confirms the first join
+    version_answered: bool,
+}
+
+type Channels = (UnboundedSender<ClientCommand>, UnboundedReceiver<ServerCommand>);
+
+async fn open_connection(options: &ConnectOptions) -> Result<Channels> {
+    let server = ServerAddress::parse(&options.server, options.tls.is_some())?;
+    let (client_command_sender, client_command_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let (server_command_sender, server_command_receiver) = tokio::sync::mpsc::unbounded_channel();
+    connect(&server, options.tls.as_ref(), options.ip_preference,
+            server_command_sender, client_command_receiver).await?;
+    Ok((client_command_sender, server_command_receiver))
 }
 
 impl IrcDccClient {
-    pub async fn connect(server: &str) -> Result<Self> {
-        let (client_command_sender, client_command_receiver) = tokio::sync::mpsc::unbounded_channel();
-        let (server_command_sender, server_command_receiver) = tokio::sync::mpsc::unbounded_channel();
-        connect(server, server_command_sender, client_command_receiver).await?;
+    /// Connects to the first reachable server, the others are used when reconnecting
+    pub async fn connect(servers: Vec<ConnectOptions>) -> Result<Self> {
+        let mut last_error = None;
+        let mut connected = None;
+        for (index, options) in servers.iter().enumerate() {
+            match open_connection(options).await {
+                Ok(channels) => {
+                    connected = Some((index, channels));
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("Failed to connect to {}: {:?}", options.server, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        let (current_server, (client_command_sender, server_command_receiver)) = match (connected, last_error) {
+            (Some(connected), _) => connected,
+            (None, Some(e)) => return Err(e),
+            (None, None) => return Err(anyhow!("No server to connect to")),
+        };
         let client = Self {
             client_command_sender,
             server_command_receiver,
+            servers,
+            current_server,
+            requested_nickname: None,
+            nickname: None,
+            nickserv: None,
+            channels: Vec::new(),
+            version_answered: false,
         };
         Ok(client)
     }
 
-    async fn wait_for_ping(&mut self) -> Result<()> {
+    /// Opens a new connection to the next server after the last one was closed,
+    /// logs in again and joins the same channels
+    pub async fn reconnect(&mut self) -> Result<()> {
+        self.current_server = (self.current_server + 1) % self.servers.len();
+        log::info!("Reconnecting to {}.", self.server());
+        let (client_command_sender, server_command_receiver) =
+            open_connection(&self.servers[self.current_server]).await?;
+        self.client_command_sender = client_command_sender;
+        self.server_command_receiver = server_command_receiver;
+        self.version_answered = false;
+        if let Some(nickname) = self.requested_nickname.clone() {
+            self.login(nickname).await?;
+        }
+        if let Some(credentials) = self.nickserv.clone() {
+            self.identify(credentials).await?;
+        }
+        for channel in self.channels.clone() {
+            self.join(channel).await?;
+        }
+        Ok(())
+    }
+
+    pub fn server(&self) -> &str {
+        &self.servers[self.current_server].server
+    }
+
+    pub fn channels(&self) -> &[String] {
+        &self.channels
+    }
+
+    /// Waits for the first PING, appending underscores to the nickname while it is already in use.
+    /// Returns the accepted nickname.
+    async fn wait_for_ping(&mut self, nickname: &str) -> Result<String> {
+        let mut current = nickname.to_string();
         loop {
             let message = match self.server_command_receiver.recv().await {
                 Some(message) => message,
@@ -39,48 +158,126 @@ impl IrcDccClient {
             match message {
                 ServerCommand::Ping(content) => {
                     self.client_command_sender.send(Pong(content))?;
-                    return Ok(());
+                    return Ok(current);
+                }
+                ServerCommand::NicknameInUse(in_use) if current.len() < nickname.len() + MAX_NICKNAME_SUFFIX => {
+                    current.push('_');
+                    log::warn!("Nickname {} is already in use, trying {}.", in_use, current);
+                    self.client_command_sender.send(ClientCommand::Nick(current.clone()))?;
                 }
+                ServerCommand::NicknameInUse(_) => bail!("Nickname {} is already in use, also with up to {} \
+                    underscores, use --nickname to choose another one", nickname, MAX_NICKNAME_SUFFIX),
                 _ => {}
             }
         }
     }
 
     pub async fn login(&mut self, nickname: String) -> Result<()> {
+        self.requested_nickname = Some(nickname.clone());
         self.client_command_sender.send(ClientCommand::Nick(nickname.clone()))?;
-        self.client_command_sender.send(ClientCommand::User(nickname))?;
+        self.client_command_sender.send(ClientCommand::User(nickname.clone()))?;
 
         // Login is successful, once we get the first PING
-        self.wait_for_ping().await
+        self.nickname = Some(self.wait_for_ping(&nickname).await?);
+        Ok(())
     }
 
-    async fn wait_for_ctcp_version(&mut self) -> Result<()> {
+    /// Waits until NickServ or the server confirm that we are logged in
+    async fn wait_for_identify(&mut self) -> Result<()> {
         loop {
             let message = match self.server_command_receiver.recv().await {
                 Some(message) => message,
-                None => bail!("Cannot receive CTCP VERSION command: channel closed"),
+                None => bail!("Cannot identify with NickServ: channel closed"),
             };
-            if let ServerCommand::PrivMsg(request) = message {
-                if let Some(ctcp_request) = CtcpRequest::try_from_request(request) {
-                    match ctcp_request.request_type {
-                        CtcpRequestType::Version => {
+            match message {
+                ServerCommand::Ping(content) => self.client_command_sender.send(Pong(content))?,
+                ServerCommand::LoggedIn(account) => {
+                    log::info!("Logged in as {}.", account);
+                    return Ok(());
+                }
+                ServerCommand::Notice(message) if message.sender.eq_ignore_ascii_case("NickServ") => {
+                    match NickServReply::try_from(message.message.as_str()) {
+                        Ok(NickServReply::Identified) => {
+                            log::info!("Identified with NickServ.");
+                            return Ok(());
+                        }
+                        Ok(NickServReply::Rejected) => {
+                            log::warn!("NickServ rejected the credentials: {}", message.message);
+                            return Ok(());
+                        }
+                        Err(()) => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Identifies with NickServ and waits for the confirmation, before joining channels which require it
+    pub async fn identify(&mut self, credentials: NickServ) -> Result<()> {
+        // with an alternative nickname, NickServ needs the account of the requested one
+        let renamed = self.requested_nickname.clone().filter(|requested| self.nickname.as_ref() != Some(requested));
+        let message = match credentials.account.as_ref().or(renamed.as_ref()) {
+            Some(account) => format!("IDENTIFY {} {}", account, credentials.password),
+            None => format!("IDENTIFY {}", credentials.password),
+        };
+        self.client_command_sender.send(ClientCommand::PrivMsg(MessageTo {
+            recipient: "NickServ".to_string(),
+            message,
+        }))?;
+        self.nickserv = Some(credentials);
+
+        match timeout(IDENTIFY_TIMEOUT, self.wait_for_identify()).await {
+            Ok(result) => result,
+            Err(_) => {
+                log::warn!("NickServ did not confirm identifying, continuing anyway.");
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits for the end of the names of the channel, or the first CTCP VERSION of the connection
+    async fn wait_for_join(&mut self, channel: &str) -> Result<()> {
+        loop {
+            let message = match self.server_command_receiver.recv().await {
+                Some(message) => message,
+                None => bail!("Cannot join {}: channel closed", channel),
+            };
+            match message {
+                ServerCommand::Ping(content) => self.client_command_sender.send(Pong(content))?,
+                ServerCommand::EndOfNames(name) if name.eq_ignore_ascii_case(channel) => return Ok(()),
+                ServerCommand::PrivMsg(request) => {
+                    if let Some(ctcp_request) = CtcpRequest::try_from_request(request) {
+                        if let CtcpRequestType::Version = ctcp_request.request_type {
                             if let CtcpReply::Message(reply) = ctcp_request.generate_reply() {
                                 self.client_command_sender.send(Notice(reply))?;
-                                return Ok(());
+                                if !self.version_answered {
+                                    self.version_answered = true;
+                                    return Ok(());
+                                }
                             }
                         }
-                        _ => {}
                     }
                 }
+                _ => {}
             }
         }
     }
 
     pub async fn join(&mut self, channel: String) -> Result<()> {
-        self.client_command_sender.send(ClientCommand::Join(channel))?;
+        if !self.channels.contains(&channel) {
+            self.channels.push(channel.clone());
+        }
+        self.client_command_sender.send(ClientCommand::Join(channel.clone()))?;
 
-        // Join is successful, once we get the first CTCP message (VERSION)
-        self.wait_for_ctcp_version().await
+        // Join is successful, once the server sent the names of the channel or its CTCP VERSION
+        match timeout(JOIN_TIMEOUT, self.wait_for_join(&channel)).await {
+            Ok(result) => result,
+            Err(_) => {
+                log::warn!("The server did not confirm joining {}, continuing anyway.", channel);
+                Ok(())
+            }
+        }
     }
 
     pub fn send_dcc_request(&mut self, request: Xdcc) -> Result<()> {
@@ -89,15 +286,30 @@ impl IrcDccClient {
         Ok(())
     }
 
+    pub fn send_dcc_resume(&mut self, resume: Resume) -> Result<()> {
+        self.client_command_sender.send(ClientCommand::PrivMsg(resume.into()))?;
+        Ok(())
+    }
+
+    pub fn send_passive_dcc(&mut self, send: PassiveSend) -> Result<()> {
+        self.client_command_sender.send(ClientCommand::PrivMsg(send.into()))?;
+        Ok(())
+    }
+
     pub async fn quit(&mut self) -> Result<()> {
         self.client_command_sender.send(ClientCommand::Quit("Goodbye".to_string()))?;
         self.server_command_receiver.close();
         // receive remaining server commands
-        while let Some(_) = self.server_command_receiver.recv().await {}
+        while self.server_command_receiver.recv().await.is_some() {}
         Ok(())
     }
 
-    pub async fn wait_for_dcc(&mut self) -> Result<Option<Dcc>> {
+    /// Whether the message was sent to our nickname instead of a channel
+    fn is_to_us(&self, message: &MessageFrom) -> bool {
+        self.nickname.as_ref().is_some_and(|nickname| nickname.eq_ignore_ascii_case(&message.target))
+    }
+
+    pub async fn wait_for_bot_message(&mut self) -> Result<Option<BotMessage>> {
         loop {
             let message = match self.server_command_receiver.recv().await {
                 Some(message) => message,
@@ -105,17 +317,24 @@ impl IrcDccClient {
             };
             match message {
                 ServerCommand::Ping(content) => self.client_command_sender.send(Pong(content))?,
-                ServerCommand::PrivMsg(message) => {
+                ServerCommand::PrivMsg(message) if message.message.starts_with('\x01') => {
                     if let Some(ctcp_reply) = CtcpRequest::try_from_request(message)
                         .map(|request| request.generate_reply()) {
                         match ctcp_reply {
-                            CtcpReply::Dcc(dcc) => return Ok(Some(dcc)),
+                            CtcpReply::Dcc(dcc) => return Ok(Some(BotMessage::Dcc(dcc))),
                             CtcpReply::Message(reply) => {
                                 self.client_command_sender.send(Notice(reply))?
                             }
                         }
                     }
                 }
+                // most bots reply using NOTICE, some using plain messages,
+                // replies in channels could be sent by anyone
+                ServerCommand::PrivMsg(message) | ServerCommand::Notice(message) if self.is_to_us(&message) => {
+                    if let Ok(reply) = XdccReply::try_from(message.message.as_str()) {
+                        return Ok(Some(BotMessage::Xdcc { sender: message.sender, reply }));
+                    }
+                }
                 _ => {}
             };
         }
@@ -1,27 +1,160 @@
-use anyhow::Result;
+use std::fmt;
+use std::net::Ipv6Addr;
+
+use anyhow::{anyhow, bail, Result};
 use futures::StreamExt;
-use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio_util::codec::{FramedRead, LinesCodec};
 
 use crate::irc::command::{ClientCommand, ServerCommand};
+use crate::irc::IpPreference;
+use crate::irc::tls::{wrap, TlsOptions};
+
+const DEFAULT_PORT: u16 = 6667;
+const DEFAULT_TLS_PORT: u16 = 6697;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ServerAddress {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) tls: bool,
+}
+
+impl ServerAddress {
+    /// Parses `host[:port]`, `irc://host[:port]` or `ircs://host[:port]`, IPv6 addresses need brackets
+    /// if they have a port. The port defaults to 6697 for TLS connections and 6667 otherwise.
+    pub(crate) fn parse(value: &str, tls: bool) -> Result<Self> {
+        Self::parse_with_port(value, tls, None)
+    }
+
+    /// Like `parse`, with the port to use instead of the default port if the address has none
+    pub(crate) fn parse_with_port(value: &str, tls: bool, default_port: Option<u16>) -> Result<Self> {
+        let (address, tls) = if let Some(address) = value.strip_prefix("ircs://") {
+            (address, true)
+        } else if let Some(address) = value.strip_prefix("irc://") {
+            (address, tls)
+        } else {
+            (value, tls)
+        };
+        let address = address.trim_end_matches('/');
+        let default_port = default_port.unwrap_or(if tls { DEFAULT_TLS_PORT } else { DEFAULT_PORT });
+
+        let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+            // IPv6 literal, e.g. [::1]:6697
+            let (host, rest) = rest.split_once(']')
+                .ok_or_else(|| anyhow!("Invalid server address: {}", value))?;
+            (host, rest.strip_prefix(':'))
+        } else if address.parse::<Ipv6Addr>().is_ok() {
+            (address, None)
+        } else {
+            match address.rsplit_once(':') {
+                // the port of an IPv6 address cannot be told apart from its last group
+                Some((host, _)) if host.contains(':') => {
+                    bail!("IPv6 addresses with a port must use brackets, e.g. [::1]:6667: {}", value)
+                }
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port.parse::<u16>()
+                .map_err(|_| anyhow!("Invalid port in server address: {}", value))?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(anyhow!("Missing host in server address: {}", value));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    /// Formats the address so that `parse` returns it again
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "ircs://" } else { "" };
+        if self.host.contains(':') {
+            write!(f, "{}[{}]:{}", scheme, self.host, self.port)
+        } else {
+            write!(f, "{}{}:{}", scheme, self.host, self.port)
+        }
+    }
+}
+
+async fn connect_tcp(server: &ServerAddress, ip_preference: IpPreference) -> Result<TcpStream> {
+    let addresses = lookup_host((server.host.as_str(), server.port)).await?
+        .filter(|address| match ip_preference {
+            IpPreference::Any => true,
+            IpPreference::V4 => address.is_ipv4(),
+            IpPreference::V6 => address.is_ipv6(),
+        })
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        bail!("No matching address found for {} ({:?})", server.host, ip_preference);
+    }
+
+    let mut last_error = None;
+    for address in addresses {
+        match TcpStream::connect(address).await {
+            Ok(stream) => {
+                log::info!("Connected to {} ({})", server.host, address);
+                return Ok(stream);
+            }
+            Err(e) => {
+                log::warn!("Failed to connect to {} ({}): {}", server.host, address, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap().into())
+}
 
 pub(crate) async fn connect(
-    server: &str,
+    server: &ServerAddress,
+    tls: Option<&TlsOptions>,
+    ip_preference: IpPreference,
     server_command_sender: UnboundedSender<ServerCommand>,
     client_command_receiver: UnboundedReceiver<ClientCommand>,
 ) -> Result<()> {
-    let stream = TcpStream::connect(server).await?;
-    let (reader, writer) = stream.into_split();
+    let stream = connect_tcp(server, ip_preference).await?;
+    let default_tls = TlsOptions::default();
+    let tls = match tls {
+        Some(tls) => Some(tls),
+        // ircs:// without any explicit TLS options
+        None if server.tls => Some(&default_tls),
+        None => None,
+    };
+    match tls {
+        Some(tls) => {
+            let stream = wrap(stream, &server.host, tls).await?;
+            log::info!("Established TLS connection to {}:{}", server.host, server.port);
+            spawn_tasks(stream, server_command_sender, client_command_receiver);
+        }
+        None => spawn_tasks(stream, server_command_sender, client_command_receiver),
+    }
+    Ok(())
+}
+
+fn spawn_tasks<S>(
+    stream: S,
+    server_command_sender: UnboundedSender<ServerCommand>,
+    client_command_receiver: UnboundedReceiver<ClientCommand>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
     tokio::spawn(read_server_commands(reader, server_command_sender));
     tokio::spawn(write_client_commands(writer, client_command_receiver));
-    Ok(())
 }
 
-async fn read_server_commands(
-    reader: OwnedReadHalf,
+async fn read_server_commands<R: AsyncRead + Unpin>(
+    reader: R,
     command_sender: UnboundedSender<ServerCommand>,
 ) {
     let mut reader = FramedRead::new(reader, LinesCodec::new());
@@ -49,35 +182,42 @@ async fn read_server_commands(
     }
 }
 
-async fn shutdown_writer(
-    mut writer: OwnedWriteHalf,
+async fn shutdown_writer<W: AsyncWrite + Unpin>(
+    mut writer: W,
     mut command_receiver: UnboundedReceiver<ClientCommand>,
 ) {
     command_receiver.close();
     // receive remaining messages
-    while let Some(_) = command_receiver.recv().await {}
+    while command_receiver.recv().await.is_some() {}
     if let Err(e) = writer.shutdown().await {
         log::error!("[Internal] Failed to shutdown writer: {}", e);
     }
 }
 
-async fn write_client_commands(
-    mut writer: OwnedWriteHalf,
+async fn write_command<W: AsyncWrite + Unpin>(writer: &mut W, command_str: &str) -> std::io::Result<()> {
+    writer.write_all(command_str.as_bytes()).await?;
+    // TLS streams buffer writes until they are flushed
+    writer.flush().await
+}
+
+async fn write_client_commands<W: AsyncWrite + Unpin>(
+    mut writer: W,
     mut command_receiver: UnboundedReceiver<ClientCommand>,
 ) {
     loop {
         let command = match command_receiver.recv().await {
             Some(command) => command,
             None => {
-                log::error!("[Internal] Writer error: channel closed");
+                // the client was dropped or replaced its connection
+                log::debug!("[Internal] Writer stopped: channel closed");
                 shutdown_writer(writer, command_receiver).await;
                 return;
             }
         };
 
         let command_str: String = (&command).into();
-        log::trace!("[Client] {}", command_str.trim());
-        if let Err(e) = writer.write_all(&command_str.as_bytes()).await {
+        log::trace!("[Client] {}", command.redacted());
+        if let Err(e) = write_command(&mut writer, &command_str).await {
             log::error!("[Internal] Writer error: {}", e);
             shutdown_writer(writer, command_receiver).await;
             return;
@@ -90,3 +230,57 @@ async fn write_client_commands(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(host: &str, port: u16, tls: bool) -> ServerAddress {
+        ServerAddress { host: host.to_string(), port, tls }
+    }
+
+    #[test]
+    fn parses_hosts_and_ports() {
+        assert_eq!(ServerAddress::parse("irc.rizon.net", false).unwrap(), address("irc.rizon.net", 6667, false));
+        assert_eq!(ServerAddress::parse("irc.rizon.net", true).unwrap(), address("irc.rizon.net", 6697, true));
+        assert_eq!(ServerAddress::parse("irc.rizon.net:7000", false).unwrap(), address("irc.rizon.net", 7000, false));
+        assert_eq!(ServerAddress::parse("ircs://irc.rizon.net/", false).unwrap(), address("irc.rizon.net", 6697, true));
+        assert_eq!(ServerAddress::parse("irc://irc.rizon.net:6660", true).unwrap(),
+                   address("irc.rizon.net", 6660, true));
+    }
+
+    #[test]
+    fn parses_ipv6_addresses() {
+        assert_eq!(ServerAddress::parse("[::1]:6697", false).unwrap(), address("::1", 6697, false));
+        assert_eq!(ServerAddress::parse("[2001:db8::1]", false).unwrap(), address("2001:db8::1", 6667, false));
+        assert_eq!(ServerAddress::parse("ircs://[2001:db8::1]", false).unwrap(), address("2001:db8::1", 6697, true));
+        // without brackets the address cannot have a port
+        assert_eq!(ServerAddress::parse("::1", false).unwrap(), address("::1", 6667, false));
+        assert_eq!(ServerAddress::parse("2001:db8::1", false).unwrap(), address("2001:db8::1", 6667, false));
+        assert!(ServerAddress::parse("2001:db8::1:6697x", false).is_err());
+        assert!(ServerAddress::parse("fe80::1%eth0:6667", false).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        assert!(ServerAddress::parse("", false).is_err());
+        assert!(ServerAddress::parse(":6667", false).is_err());
+        assert!(ServerAddress::parse("irc.rizon.net:port", false).is_err());
+        assert!(ServerAddress::parse("irc.rizon.net:70000", false).is_err());
+        assert!(ServerAddress::parse("[::1", false).is_err());
+    }
+    #[test]
+    fn formats_parsable_addresses() {
+        for (value, formatted) in [
+            ("irc.rizon.net", "irc.rizon.net:7000"),
+            ("ircs://irc.rizon.net", "ircs://irc.rizon.net:7000"),
+            ("irc.rizon.net:6697", "irc.rizon.net:6697"),
+            ("2001:db8::1", "[2001:db8::1]:7000"),
+            ("[2001:db8::1]", "[2001:db8::1]:7000"),
+        ] {
+            let address = ServerAddress::parse_with_port(value, false, Some(7000)).unwrap();
+            assert_eq!(address.to_string(), formatted);
+            assert_eq!(ServerAddress::parse(formatted, false).unwrap(), address);
+        }
+    }
+}
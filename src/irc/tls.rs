@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+#[derive(Debug, Clone)]
+pub enum TlsVerification {
+    // Verify the certificate chain against the bundled root certificates
+    WebPki,
+    // Only accept a server certificate with the given SHA-256 fingerprint
+    Fingerprint([u8; 32]),
+    // Accept any certificate, including self-signed ones
+    AcceptInvalid,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub verification: TlsVerification,
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self {
+            verification: TlsVerification::WebPki,
+        }
+    }
+}
+
+/// Parses a SHA-256 fingerprint given as hex, optionally separated by colons
+pub fn parse_fingerprint(value: &str) -> Result<[u8; 32]> {
+    let hex = value.replace(':', "");
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Expected a SHA-256 fingerprint with 32 hex encoded bytes, got {}", value);
+    }
+    let mut fingerprint = [0u8; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("Invalid hex in fingerprint {}", value))?;
+    }
+    Ok(fingerprint)
+}
+
+fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":")
+}
+
+#[derive(Debug)]
+struct CustomVerifier {
+    provider: Arc<CryptoProvider>,
+    fingerprint: Option<[u8; 32]>,
+}
+
+impl ServerCertVerifier for CustomVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = match self.fingerprint {
+            Some(fingerprint) => fingerprint,
+            None => return Ok(ServerCertVerified::assertion()),
+        };
+        let actual: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+        if actual == fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            log::error!("Server certificate fingerprint {} does not match the pinned fingerprint {}",
+                format_fingerprint(&actual), format_fingerprint(&fingerprint));
+            Err(rustls::Error::General("Certificate fingerprint mismatch".to_string()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn client_config(options: &TlsOptions) -> Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let config = match options.verification {
+        TlsVerification::WebPki => {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsVerification::Fingerprint(fingerprint) => builder.dangerous()
+            .with_custom_certificate_verifier(Arc::new(CustomVerifier {
+                provider,
+                fingerprint: Some(fingerprint),
+            }))
+            .with_no_client_auth(),
+        TlsVerification::AcceptInvalid => builder.dangerous()
+            .with_custom_certificate_verifier(Arc::new(CustomVerifier {
+                provider,
+                fingerprint: None,
+            }))
+            .with_no_client_auth(),
+    };
+    Ok(config)
+}
+
+pub(crate) async fn wrap(stream: TcpStream, host: &str, options: &TlsOptions) -> Result<TlsStream<TcpStream>> {
+    let connector = TlsConnector::from(Arc::new(client_config(options)?));
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|_| anyhow!("Invalid TLS server name: {}", host))?;
+    let stream = connector.connect(server_name, stream).await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: &str = "0123456789abcdef0123456789ABCDEF0123456789abcdef0123456789ABCDEF";
+
+    #[test]
+    fn parses_fingerprints() {
+        let fingerprint = parse_fingerprint(FINGERPRINT).unwrap();
+        assert_eq!(fingerprint[..4], [0x01, 0x23, 0x45, 0x67]);
+        assert_eq!(fingerprint[31], 0xef);
+        let separated = fingerprint.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":");
+        assert_eq!(parse_fingerprint(&separated).unwrap(), fingerprint);
+        assert_eq!(parse_fingerprint(&format_fingerprint(&fingerprint)).unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn rejects_wrong_lengths() {
+        assert!(parse_fingerprint("").is_err());
+        assert!(parse_fingerprint(&FINGERPRINT[..62]).is_err());
+        assert!(parse_fingerprint(&format!("{}00", FINGERPRINT)).is_err());
+    }
+
+    #[test]
+    fn rejects_non_hex_input() {
+        assert!(parse_fingerprint(&FINGERPRINT.replace('0', "g")).is_err());
+        assert!(parse_fingerprint(&FINGERPRINT.replacen("01", "+1", 1)).is_err());
+        // multi-byte characters must not split the input inside a character
+        assert!(parse_fingerprint(&format!("ä{}", &FINGERPRINT[2..])).is_err());
+    }
+}
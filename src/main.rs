@@ -1,51 +1,684 @@
-use anyhow::{anyhow, Result};
-use clap::Parser;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
 
-use crate::irc::command::xdcc::Xdcc;
-use crate::irc::IrcDccClient;
-use crate::package_downloader::PackageDownloader;
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use regex::{Regex, RegexBuilder};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
 
+use crate::config::Config;
+use crate::daemon::{private_temp_dir, run_daemon, send_request, Request, Response};
+use crate::download_path::{DownloadPaths, ExistingFilePolicy};
+use crate::event::Event;
+use crate::irc::command::ctcp::dcc::{AckMode, PassiveDcc, TransferOptions};
+use crate::irc::command::xdcc::{BatchStyle, Xdcc};
+use crate::irc::{ConnectOptions, IpPreference, IrcDccClient, NickServ};
+use crate::irc::tls::{parse_fingerprint, TlsOptions, TlsVerification};
+use crate::output::{start_output, OutputFormat};
+use crate::pack_info::fetch_pack_info;
+use crate::pack_list::{fetch_pack_list, ListFormat};
+use crate::package_downloader::{validate_command, DownloadCommand, DownloaderOptions, PackageDownloader};
+use crate::queue_file::{QueueEntry, StateFile};
+use crate::summary::Failure;
+
+mod config;
+mod daemon;
+mod download_path;
+mod event;
+mod filename;
 mod irc;
+mod output;
+mod pack_info;
+mod pack_list;
 mod package_downloader;
+mod progress;
+mod queue_file;
+mod summary;
 
 /// Program to run an XDCC command in an IRC server
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true,
+          subcommand_value_name = "SUBCOMMAND")]
 struct Args {
-    // XDCC command to run
-    #[arg()]
-    command: String,
-    // IRC network to connect to
-    #[arg(short, long, default_value="irc.rizon.net:6667")]
-    server: String,
-    // Channel to join upon connecting
-    #[arg(short, long, default_value="#nibl")]
-    channel: String,
-    // Nickname for connecting to the IRC network
-    #[arg(short, long, default_value="xdcc-cli")]
+    /// XDCC commands to run, `-` reads commands from stdin, one per line
+    #[arg(value_name = "COMMAND", required_unless_present = "from_file")]
+    commands: Vec<String>,
+    /// Read XDCC commands from this file, one per line,
+    /// followed by optional server=, channel= and output-dir= overrides
+    #[arg(long, value_name = "FILE")]
+    from_file: Option<PathBuf>,
+    /// File to record downloaded packs in, which are skipped when running again,
+    /// defaults to the file of --from-file with .state appended
+    #[arg(long, value_name = "FILE")]
+    state_file: Option<PathBuf>,
+    #[command(subcommand)]
+    subcommand: Option<Command>,
+    /// IRC network to connect to, use ircs://host:port for TLS, repeat to try further servers
+    #[arg(short, long = "server", value_name = "SERVER", default_value="irc.rizon.net", global = true)]
+    servers: Vec<String>,
+    /// Network of the config file to use
+    #[arg(long, global = true)]
+    network: Option<String>,
+    /// Config file, defaults to $XDG_CONFIG_HOME/xdcc-cli/config.toml
+    #[arg(long, value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+    /// Only connect to the IRC network using IPv4
+    #[arg(short = '4', long, conflicts_with = "ipv6", global = true)]
+    ipv4: bool,
+    /// Only connect to the IRC network using IPv6
+    #[arg(short = '6', long, global = true)]
+    ipv6: bool,
+    /// Connect to the IRC network using TLS
+    #[arg(long, global = true)]
+    tls: bool,
+    /// Only accept the server certificate with this SHA-256 fingerprint (implies --tls)
+    #[arg(long, global = true)]
+    tls_fingerprint: Option<String>,
+    /// Accept invalid and self-signed server certificates (implies --tls)
+    #[arg(long, conflicts_with = "tls_fingerprint", global = true)]
+    tls_insecure: bool,
+    /// Channel to join upon connecting, repeat to join several channels
+    #[arg(short, long = "channel", value_name = "CHANNEL", default_value="#nibl", global = true)]
+    channels: Vec<String>,
+    /// Nickname for connecting to the IRC network
+    #[arg(short, long, default_value="xdcc-cli", global = true)]
     nickname: String,
-    // Seconds to wait for downloads before timing out
-    #[arg(short, long, default_value="30")]
+    /// Seconds to wait for downloads before timing out
+    #[arg(short, long, default_value="30", global = true)]
     timeout_seconds: u64,
+    /// How many bots to download from at the same time, all of them by default
+    #[arg(long, global = true)]
+    concurrency: Option<usize>,
+    /// How often to request a failed pack again
+    #[arg(long, default_value = "3", global = true)]
+    retries: u32,
+    /// Seconds to wait before the first retry, doubled for every further retry
+    #[arg(long, default_value = "5", global = true)]
+    retry_delay: u64,
+    /// How often to reconnect after the connection to the network was closed
+    #[arg(long, default_value = "5", global = true)]
+    reconnects: u32,
+    /// How to request batches: batch (one request using ranges) or single (one request per pack)
+    #[arg(long, value_enum, default_value = "batch", global = true)]
+    batch_style: BatchStyle,
+    /// IP address to advertise for passive DCC, enables passive (reverse) transfers
+    #[arg(long, global = true)]
+    dcc_ip: Option<IpAddr>,
+    /// Port range to listen on for passive DCC, e.g. 40000-40100
+    #[arg(long, value_parser = parse_port_range, global = true)]
+    dcc_ports: Option<RangeInclusive<u16>>,
+    /// Also accept packs sent by this nickname, e.g. when a bot sends from another nick
+    #[arg(long = "dcc-allow-sender", value_name = "NICKNAME", global = true)]
+    dcc_allowed_senders: Vec<String>,
+    /// Accept offers from private or loopback addresses, e.g. for bots in the local network
+    #[arg(long, global = true)]
+    dcc_allow_private: bool,
+    /// How to acknowledge received data: full (32-bit), full64 (64-bit) or turbo (none)
+    #[arg(long, value_enum, default_value = "full", global = true)]
+    dcc_ack: AckMode,
+    /// Directory to save downloads to
+    #[arg(short, long, default_value = ".", global = true)]
+    output_dir: PathBuf,
+    /// Path of downloaded files relative to the output directory,
+    /// supports {bot}, {date}, {filename} and {pack}
+    #[arg(short, long, default_value = "{filename}", global = true)]
+    filename_template: String,
+    /// What to do if a downloaded file already exists
+    #[arg(short, long, value_enum, default_value = "resume", global = true)]
+    existing: ExistingFilePolicy,
+    /// Output format: text or newline-delimited JSON events
+    #[arg(long = "output", value_enum, default_value = "text", global = true)]
+    output_format: OutputFormat,
+    /// Unix socket of the daemon, defaults to xdcc-cli.sock in $XDG_RUNTIME_DIR or a private temporary directory
+    #[arg(long, global = true)]
+    socket: Option<PathBuf>,
+    // Set by the network of the config file
+    #[arg(skip)]
+    nickserv: Option<NickServ>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Fetch and print the pack list of a bot
+    List {
+        /// Nickname of the bot
+        bot: String,
+        /// Only show packs with a filename matching this regular expression
+        #[arg(long, value_parser = Regex::new)]
+        filter: Option<Regex>,
+        /// How to print the packs: text, json or csv
+        #[arg(long, value_enum, default_value = "text")]
+        format: ListFormat,
+    },
+    /// Print the packs of a bot with a filename matching the pattern, ignoring case
+    Search {
+        /// Nickname of the bot
+        bot: String,
+        /// Regular expression to search for
+        #[arg(value_parser = parse_pattern)]
+        pattern: Regex,
+        /// How to print the packs: text, json or csv
+        #[arg(long, value_enum, default_value = "text")]
+        format: ListFormat,
+    },
+    /// Download all packs of a bot with a filename matching the pattern, ignoring case
+    Get {
+        /// Nickname of the bot
+        bot: String,
+        /// Regular expression the filenames have to match
+        #[arg(long = "match", value_parser = parse_pattern)]
+        pattern: Regex,
+    },
+    /// Stay connected and download the commands added with `queue add`, until interrupted
+    Daemon {
+        /// File to keep the queue in, so queued commands survive restarts
+        #[arg(long, default_value = "xdcc-cli-queue.json")]
+        queue_file: PathBuf,
+    },
+    /// Control a running daemon
+    Queue {
+        #[command(subcommand)]
+        action: QueueAction,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum QueueAction {
+    /// Add XDCC commands to the queue, `-` reads commands from stdin
+    Add {
+        #[arg(required = true)]
+        commands: Vec<String>,
+    },
+    /// Print the queued, finished and cancelled commands
+    List,
+    /// Stop downloading a command
+    Cancel {
+        /// Id of the command, as printed by `queue add` and `queue list`
+        id: u64,
+    },
+    /// Do not send further commands to the bots, running downloads continue
+    Pause,
+    /// Send queued commands to the bots again
+    Resume,
+}
+
+/// Replaces `-` with the lines of stdin
+fn read_stdin_commands(commands: &[String]) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for command in commands {
+        if command == "-" {
+            lines.extend(std::io::stdin().lines().collect::<std::io::Result<Vec<_>>>()?);
+        } else {
+            lines.push(command.clone());
+        }
+    }
+    Ok(lines)
+}
+
+fn skip_comments(lines: Vec<String>) -> Vec<String> {
+    lines.into_iter()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+fn parse_pattern(value: &str) -> Result<Regex> {
+    Ok(RegexBuilder::new(value).case_insensitive(true).build()?)
+}
+
+fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>> {
+    let (start, end) = value.split_once('-').unwrap_or((value, value));
+    let range = start.trim().parse::<u16>()?..=end.trim().parse::<u16>()?;
+    if range.is_empty() {
+        return Err(anyhow!("Invalid port range: {}", value));
+    }
+    Ok(range)
+}
+
+/// Whether the user left the argument at its default value
+fn is_default(matches: &ArgMatches, id: &str) -> bool {
+    matches!(matches.value_source(id), None | Some(ValueSource::DefaultValue))
 }
 
+impl Args {
+    /// Takes the values the user did not pass on the command line from the config file
+    fn apply_config(&mut self, matches: &ArgMatches) -> Result<()> {
+        let config = Config::load(self.config.as_deref())?;
+        let network = config.network(self.network.as_deref())?;
+        if let Some(network) = network {
+            if is_default(matches, "servers") && !network.servers.is_empty() {
+                self.servers = network.server_addresses()?;
+            }
+            if is_default(matches, "channels") && !network.channels.is_empty() {
+                self.channels = network.channels.clone();
+            }
+            // a server given on the command line may not use the TLS setting of the network
+            if is_default(matches, "tls") && is_default(matches, "servers") {
+                self.tls = network.tls;
+            }
+            // the password belongs to the nickname of the config, unless the network names the account
+            if is_default(matches, "nickname") || network.nickserv_account.is_some() {
+                self.nickserv = network.nickserv();
+            }
+        }
+        let nickname = network.and_then(|network| network.nickname.clone()).or(config.nickname);
+        if let (true, Some(nickname)) = (is_default(matches, "nickname"), nickname) {
+            self.nickname = nickname;
+        }
+        if let (true, Some(timeout_seconds)) = (is_default(matches, "timeout_seconds"), config.timeout_seconds) {
+            self.timeout_seconds = timeout_seconds;
+        }
+        if let (true, Some(output_dir)) = (is_default(matches, "output_dir"), config.output_dir) {
+            self.output_dir = output_dir;
+        }
+        self.concurrency = self.concurrency.or(config.concurrency);
+        Ok(())
+    }
+
+    fn tls_options(&self) -> Result<Option<TlsOptions>> {
+        let verification = if let Some(fingerprint) = &self.tls_fingerprint {
+            TlsVerification::Fingerprint(parse_fingerprint(fingerprint)?)
+        } else if self.tls_insecure {
+            TlsVerification::AcceptInvalid
+        } else if self.tls {
+            TlsVerification::WebPki
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(TlsOptions { verification }))
+    }
+
+    fn connect_options(&self, server: &str) -> Result<ConnectOptions> {
+        let ip_preference = if self.ipv4 {
+            IpPreference::V4
+        } else if self.ipv6 {
+            IpPreference::V6
+        } else {
+            IpPreference::Any
+        };
+        Ok(ConnectOptions {
+            server: server.to_string(),
+            tls: self.tls_options()?,
+            ip_preference,
+        })
+    }
+
+    fn downloader_options(&self) -> Result<DownloaderOptions> {
+        Ok(DownloaderOptions {
+            timeout_seconds: self.timeout_seconds,
+            passive: self.passive_dcc(),
+            transfer: TransferOptions {
+                ack_mode: self.dcc_ack,
+            },
+            paths: DownloadPaths::new(self.output_dir.clone(), self.filename_template.clone(), self.existing)?,
+            max_retries: self.retries,
+            retry_delay: Duration::from_secs(self.retry_delay),
+            max_reconnects: self.reconnects,
+            max_bots: self.concurrency,
+            allowed_senders: self.dcc_allowed_senders.clone(),
+            allow_private_endpoints: self.dcc_allow_private,
+            batch_style: self.batch_style,
+            state: None,
+        })
+    }
+
+    /// Collects the commands of the arguments, stdin and the file, skipping empty lines and # comments
+    fn read_commands(&self) -> Result<Vec<String>> {
+        let mut lines = read_stdin_commands(&self.commands)?;
+        if let Some(path) = &self.from_file {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read commands from {}", path.display()))?;
+            lines.extend(content.lines().map(str::to_string));
+        }
+        Ok(skip_comments(lines))
+    }
+
+    fn socket_path(&self) -> Result<PathBuf> {
+        if let Some(socket) = &self.socket {
+            return Ok(socket.clone());
+        }
+        let directory = match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(directory) => PathBuf::from(directory),
+            None => private_temp_dir()?,
+        };
+        Ok(directory.join("xdcc-cli.sock"))
+    }
+
+    fn state_file(&self) -> Option<PathBuf> {
+        self.state_file.clone().or_else(|| self.from_file.as_ref().map(|path| {
+            let mut state_file = path.clone().into_os_string();
+            state_file.push(".state");
+            PathBuf::from(state_file)
+        }))
+    }
+
+    fn passive_dcc(&self) -> Option<PassiveDcc> {
+        self.dcc_ip.map(|ip| PassiveDcc {
+            ip,
+            ports: self.dcc_ports.clone(),
+            accept_timeout: Duration::from_secs(self.timeout_seconds),
+        })
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     // Set up logging
     env_logger::init();
-    let args = Args::parse();
-    let command = Xdcc::try_from(args.command.as_str())
-        .map_err(|err| anyhow!("Failed to parse command: {}", err))?;
-
-    let mut client = IrcDccClient::connect(&args.server).await?;
-    client.login(args.nickname).await?;
-    client.join(args.channel).await?;
-    // cancel previous transfers before starting a new one
-    client.send_dcc_request(Xdcc::Remove(command.recipient().to_string(), None))?;
-    client.send_dcc_request(Xdcc::Cancel(command.recipient().to_string()))?;
-    let downloader = PackageDownloader::new(client, command, args.timeout_seconds).await?;
-    downloader.download_packages().await?;
+    let matches = Args::command().get_matches();
+    let result = match Args::from_arg_matches(&matches) {
+        Ok(mut args) => match args.apply_config(&matches) {
+            Ok(()) => run(args).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => e.exit(),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(err.downcast_ref::<Failure>().map(Failure::exit_code).unwrap_or(1))
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    let has_commands = !args.commands.is_empty() || args.from_file.is_some() || args.state_file.is_some();
+    if has_commands && args.subcommand.is_some() {
+        return Err(Failure::BadCommand("Either pass an XDCC command or a subcommand".to_string()).into());
+    }
+    let options = args.downloader_options()?;
+    match &args.subcommand {
+        Some(Command::List { bot, filter, format }) => run_list(&args, options, bot, filter.as_ref(), *format).await,
+        Some(Command::Search { bot, pattern, format }) => run_list(&args, options, bot, Some(pattern), *format).await,
+        Some(Command::Get { bot, pattern }) => run_get(&args, options, bot, pattern).await,
+        Some(Command::Daemon { queue_file }) => run_daemon_command(&args, options, queue_file).await,
+        Some(Command::Queue { action }) => run_queue(&args, action).await,
+        None => run_commands(&args).await,
+    }
+}
+
+/// Connects to the IRC network of --server and joins the channels of --channel
+async fn connect(args: &Args, events: &UnboundedSender<Event>) -> Result<IrcDccClient> {
+    connect_to(args, &args.servers, &args.channels, events).await
+}
+
+/// Connects to the first reachable server of the IRC network and joins the channels
+async fn connect_to(args: &Args, servers: &[String], channels: &[String],
+                    events: &UnboundedSender<Event>) -> Result<IrcDccClient> {
+    let servers = servers.iter()
+        .map(|server| args.connect_options(server))
+        .collect::<Result<Vec<_>>>()?;
+    let mut client = IrcDccClient::connect(servers).await?;
+    events.send(Event::Connected { server: client.server().to_string() })?;
+    client.login(args.nickname.clone()).await?;
+    if let Some(credentials) = &args.nickserv {
+        client.identify(credentials.clone()).await?;
+    }
+    for channel in channels {
+        client.join(channel.clone()).await?;
+        events.send(Event::Joined { channel: channel.clone() })?;
+    }
+    Ok(client)
+}
+
+/// Leaves the IRC network and waits until all events are reported
+async fn shutdown<T>(mut client: IrcDccClient, events: UnboundedSender<Event>, output_handle: JoinHandle<()>,
+                     result: Result<T>) -> Result<T> {
+    // the connection may already be lost, which is reported by the result
+    let quit = client.quit().await;
+    drop(events);
+    output_handle.await?;
+    let value = result?;
+    quit?;
+    Ok(value)
+}
+
+async fn run_list(args: &Args, options: DownloaderOptions, bot: &str, filter: Option<&Regex>,
+                  format: ListFormat) -> Result<()> {
+    let (events, output_handle) = start_output(args.output_format);
+    let mut client = connect(args, &events).await?;
+    let result = fetch_pack_list(&mut client, bot, &options).await;
+    let list = shutdown(client, events, output_handle, result).await?;
+    let list = match filter {
+        Some(filter) => list.filter(filter),
+        None => list,
+    };
+    print!("{}", list.format(format)?);
     Ok(())
 }
 
+async fn run_get(args: &Args, options: DownloaderOptions, bot: &str, pattern: &Regex) -> Result<()> {
+    let (events, output_handle) = start_output(args.output_format);
+    let mut client = connect(args, &events).await?;
+    let list = match fetch_pack_list(&mut client, bot, &options).await {
+        Ok(list) => list.filter(pattern),
+        Err(e) => return shutdown(client, events, output_handle, Err(e)).await,
+    };
+    if list.packs.is_empty() {
+        let error = anyhow!("No pack of {} matches {}", bot, pattern);
+        return shutdown(client, events, output_handle, Err(error)).await;
+    }
+    for entry in &list.packs {
+        log::info!("Pack #{} of {} matches: {}", entry.pack, bot, entry.filename);
+    }
+    let command = match Xdcc::batch(bot.to_string(), list.packs.iter().map(|entry| entry.pack)) {
+        Ok(command) => command,
+        Err(e) => return shutdown(client, events, output_handle, Err(e)).await,
+    };
+    download(client, vec![command.into()], options, events, output_handle).await
+}
+
+async fn run_commands(args: &Args) -> Result<()> {
+    let entries = args.read_commands()?.iter()
+        .map(|command| QueueEntry::try_from(command.as_str())
+            .map_err(|err| Failure::BadCommand(format!("Failed to parse command: {}", err))))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let [QueueEntry { command: Xdcc::Info(bot, pack), server, channel, .. }] = entries.as_slice() {
+        let servers = server.clone().map(|server| vec![server]).unwrap_or_else(|| args.servers.clone());
+        let channels = channel.clone().map(|channel| vec![channel]).unwrap_or_else(|| args.channels.clone());
+        return run_info(args, &servers, &channels, bot, *pack).await;
+    }
+    if entries.is_empty() {
+        return Err(Failure::BadCommand("No XDCC command given!".to_string()).into());
+    }
+    if entries.iter().any(|entry| matches!(entry.command, Xdcc::Info(..))) {
+        return Err(Failure::BadCommand("XDCC INFO cannot be combined with other commands!".to_string()).into());
+    }
+    entries.iter().try_for_each(|entry| validate_command(&entry.command))?;
+
+    // every IRC network gets its own connection, one after another
+    let mut networks: Vec<(Vec<String>, Vec<QueueEntry>)> = Vec::new();
+    for entry in entries {
+        let servers = entry.server.clone().map(|server| vec![server]).unwrap_or_else(|| args.servers.clone());
+        match networks.iter_mut().find(|(other, _)| other[0].eq_ignore_ascii_case(&servers[0])) {
+            Some((_, network_entries)) => network_entries.push(entry),
+            None => networks.push((servers, vec![entry])),
+        }
+    }
+    let mut result = Ok(());
+    for (servers, entries) in networks {
+        let server_result = run_server_commands(args, &servers, entries).await;
+        if let (Err(e), Err(_)) = (&server_result, &result) {
+            log::error!("Downloading from {} failed: {:?}", servers[0], e);
+        }
+        result = result.and(server_result);
+    }
+    result
+}
+
+async fn run_info(args: &Args, servers: &[String], channels: &[String], bot: &str, pack: u32) -> Result<()> {
+    let (events, output_handle) = start_output(args.output_format);
+    let mut client = connect_to(args, servers, channels, &events).await?;
+    let result = fetch_pack_info(&mut client, bot, pack, Duration::from_secs(args.timeout_seconds)).await;
+    if let Ok(info) = &result {
+        events.send(Event::Info(info.clone()))?;
+    }
+    shutdown(client, events, output_handle, result.map(|_| ())).await
+}
+
+/// Downloads the commands from the first reachable server, packs are recorded in the state file by the first server
+async fn run_server_commands(args: &Args, servers: &[String], entries: Vec<QueueEntry>) -> Result<()> {
+    let server = &servers[0];
+    let mut options = args.downloader_options()?;
+    if let Some(path) = args.state_file() {
+        options.state = Some(StateFile::load(path, server)?);
+    }
+    let entries = entries.into_iter()
+        .filter_map(|mut entry| {
+            if let Some(state) = &options.state {
+                entry.command = state.remaining(entry.command)?;
+            }
+            Some(entry)
+        })
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        log::info!("All packs from {} are already downloaded.", server);
+        return Ok(());
+    }
+    let mut channels: Vec<String> = Vec::new();
+    for entry in &entries {
+        let entry_channels = match &entry.channel {
+            Some(channel) => std::slice::from_ref(channel),
+            None => args.channels.as_slice(),
+        };
+        for channel in entry_channels {
+            if !channels.iter().any(|other| other.eq_ignore_ascii_case(channel)) {
+                channels.push(channel.clone());
+            }
+        }
+    }
+
+    let (events, output_handle) = start_output(args.output_format);
+    let client = connect_to(args, servers, &channels, &events).await?;
+    let commands = entries.into_iter().map(DownloadCommand::from).collect();
+    download(client, commands, options, events, output_handle).await
+}
+
+async fn run_daemon_command(args: &Args, options: DownloaderOptions, queue_file: &Path) -> Result<()> {
+    let (events, _output_handle) = start_output(args.output_format);
+    let client = connect(args, &events).await?;
+    let downloader = PackageDownloader::new(client, Vec::new(), options, events).await?;
+    // the output is not awaited, the downloader keeps running until the program exits
+    run_daemon(downloader, queue_file.to_path_buf(), args.output_dir.clone(), &args.socket_path()?).await
+}
+
+async fn run_queue(args: &Args, action: &QueueAction) -> Result<()> {
+    let request = match action {
+        QueueAction::Add { commands } => Request::Add { commands: skip_comments(read_stdin_commands(commands)?) },
+        QueueAction::List => Request::List,
+        QueueAction::Cancel { id } => Request::Cancel { id: *id },
+        QueueAction::Pause => Request::Pause,
+        QueueAction::Resume => Request::Resume,
+    };
+    let response = send_request(&args.socket_path()?, &request).await?;
+    if args.output_format == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&response)?);
+    }
+    match response {
+        Response::Error { message } => Err(anyhow!(message)),
+        _ if args.output_format == OutputFormat::Json => Ok(()),
+        Response::Added { ids } => {
+            ids.iter().for_each(|id| println!("{}", id));
+            Ok(())
+        }
+        Response::Items { paused, items } => {
+            if paused {
+                eprintln!("The queue is paused.");
+            }
+            for item in items {
+                let error = item.error.map(|error| format!("  ({})", error)).unwrap_or_default();
+                println!("{:>4}  {:<9}  {}{}", item.id, item.status, item.command, error);
+            }
+            Ok(())
+        }
+        Response::Ok => Ok(()),
+    }
+}
+
+async fn download(client: IrcDccClient, commands: Vec<DownloadCommand>, options: DownloaderOptions,
+                  events: UnboundedSender<Event>, output_handle: JoinHandle<()>) -> Result<()> {
+    let downloader = PackageDownloader::new(client, commands, options, events).await?;
+    let result = downloader.download_packages().await;
+    // report all events, even if some packages failed
+    output_handle.await?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r##"
+network = "rizon"
+timeout_seconds = 60
+
+[networks.rizon]
+servers = ["irc.rizon.net", "[2001:db8::1]"]
+port = 7000
+tls = true
+nickname = "my-nick"
+nickserv_password = "secret"
+channels = ["#news"]
+
+[networks.libera]
+servers = ["irc.libera.chat"]
+nickserv_account = "account"
+nickserv_password = "secret"
+"##;
+
+    /// Parses the command line and applies the config above
+    fn parse_args(name: &str, options: &[&str]) -> Args {
+        let config = std::env::temp_dir().join(format!("xdcc-cli-test-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&config, CONFIG).unwrap();
+        let mut command_line = vec!["xdcc-cli", "--config", config.to_str().unwrap()];
+        command_line.extend(options);
+        command_line.push("/msg bot xdcc send #1");
+        let matches = Args::command().try_get_matches_from(command_line).unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        args.apply_config(&matches).unwrap();
+        std::fs::remove_file(&config).unwrap();
+        args
+    }
+
+    #[test]
+    fn uses_the_network_of_the_config() {
+        let args = parse_args("network", &[]);
+        assert_eq!(args.servers, vec!["ircs://irc.rizon.net:7000", "ircs://[2001:db8::1]:7000"]);
+        assert!(args.tls);
+        assert_eq!(args.channels, vec!["#news"]);
+        assert_eq!(args.nickname, "my-nick");
+        assert_eq!(args.timeout_seconds, 60);
+        assert!(args.nickserv.is_some_and(|nickserv| nickserv.account.is_none() && nickserv.password == "secret"));
+    }
+
+    #[test]
+    fn prefers_the_command_line() {
+        let args = parse_args("command-line", &["-s", "irc.example.net", "-c", "#nibl", "-t", "10"]);
+        assert_eq!(args.servers, vec!["irc.example.net"]);
+        // the TLS setting belongs to the servers of the network
+        assert!(!args.tls);
+        assert_eq!(args.channels, vec!["#nibl"]);
+        assert_eq!(args.timeout_seconds, 10);
+
+        let args = parse_args("tls", &["-s", "irc.example.net", "--tls"]);
+        assert!(args.tls);
+    }
+
+    #[test]
+    fn identifies_only_the_nickname_of_the_config() {
+        let args = parse_args("nickname", &["-n", "other-nick"]);
+        assert_eq!(args.nickname, "other-nick");
+        assert!(args.nickserv.is_none());
+        // the password of an account does not depend on the nickname
+        let args = parse_args("account", &["--network", "libera", "-n", "other-nick"]);
+        assert!(args.nickserv.is_some_and(|nickserv| nickserv.account.as_deref() == Some("account")));
+        assert_eq!(args.servers, vec!["irc.libera.chat"]);
+        assert!(!args.tls);
+    }
+}
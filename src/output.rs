@@ -0,0 +1,32 @@
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::event::Event;
+use crate::progress::report_progress;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    // Progress on stderr and downloaded files on stdout
+    Text,
+    // Newline-delimited JSON events on stdout
+    Json,
+}
+
+async fn write_json_events(mut receiver: UnboundedReceiver<Event>) {
+    while let Some(event) = receiver.recv().await {
+        match serde_json::to_string(&event) {
+            Ok(line) => println!("{}", line),
+            Err(e) => log::error!("[Internal] Failed to serialize event {:?}: {}", event, e),
+        }
+    }
+}
+
+/// Spawns the front-end for the output format, which runs until all event senders are dropped
+pub fn start_output(format: OutputFormat) -> (UnboundedSender<Event>, JoinHandle<()>) {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let handle = match format {
+        OutputFormat::Text => tokio::spawn(report_progress(receiver)),
+        OutputFormat::Json => tokio::spawn(write_json_events(receiver)),
+    };
+    (sender, handle)
+}
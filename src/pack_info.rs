@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::irc::command::ctcp::dcc::{Dcc, DccType};
+use crate::irc::command::xdcc::{Xdcc, XdccReply};
+use crate::irc::{BotMessage, IrcDccClient};
+use crate::summary::Failure;
+
+// The reply is complete once the bot stops sending info lines for this long
+const QUIET_PERIOD: Duration = Duration::from_secs(2);
+
+/// Details of a pack, as sent by the bot for `xdcc info`
+#[derive(Debug, Clone, Serialize)]
+pub struct PackInfo {
+    pub bot: String,
+    pub pack: u32,
+    pub filename: Option<String>,
+    pub size: Option<u64>,
+    pub md5: Option<String>,
+    pub crc32: Option<String>,
+    pub gets: Option<u32>,
+    pub added: Option<String>,
+}
+
+/// Parses sizes like `201000`, `350MB`, `1.2G` or `[195KB]`, units are powers of 1024
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().trim_start_matches('[').trim_end_matches(']').trim();
+    if let Ok(bytes) = value.parse::<u64>() {
+        return Some(bytes);
+    }
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let (number, unit) = value.split_at(split);
+    let exponent = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return None,
+    };
+    Some((number.parse::<f64>().ok()? * 1024f64.powi(exponent)) as u64)
+}
+
+impl PackInfo {
+    fn new(bot: &str, pack: u32) -> Self {
+        Self {
+            bot: bot.to_string(),
+            pack,
+            filename: None,
+            size: None,
+            md5: None,
+            crc32: None,
+            gets: None,
+            added: None,
+        }
+    }
+
+    fn set_field(&mut self, name: &str, value: String) {
+        // values may be followed by details, e.g. `201000 [195KB]` or `42 times`
+        let first_word = value.split_whitespace().next().unwrap_or_default();
+        match name {
+            "filename" => self.filename = Some(value),
+            "filesize" => self.size = parse_size(first_word).or_else(|| parse_size(&value)),
+            "md5sum" => self.md5 = Some(value),
+            "crc32" => self.crc32 = Some(value),
+            "gets" => self.gets = first_word.parse().ok(),
+            "added" | "last modified" => self.added = Some(value),
+            _ => {}
+        }
+    }
+}
+
+/// Requests the info of a pack and collects the reply of the bot
+pub async fn fetch_pack_info(client: &mut IrcDccClient, bot: &str, pack: u32,
+                             timeout_duration: Duration) -> Result<PackInfo> {
+    client.send_dcc_request(Xdcc::Info(bot.to_string(), pack))?;
+    let mut info = PackInfo::new(bot, pack);
+    let mut received = false;
+    loop {
+        let wait = if received { QUIET_PERIOD } else { timeout_duration };
+        let message = match timeout(wait, client.wait_for_bot_message()).await {
+            Ok(message) => message?,
+            Err(_) if received => return Ok(info),
+            Err(_) => return Err(anyhow!(Failure::Timeout { failed: 1, total: 1 })
+                .context(format!("{} did not send the info of pack #{}", bot, pack))),
+        };
+        let reply = match message {
+            Some(BotMessage::Xdcc { sender, reply }) if sender.eq_ignore_ascii_case(bot) => reply,
+            Some(BotMessage::Dcc(Dcc { sender, dcc_type: DccType::Send(send) })) => {
+                log::warn!("Rejecting DCC SEND of {} from {}: only the info of pack #{} was requested",
+                    send.filename, sender, pack);
+                continue;
+            }
+            Some(BotMessage::Dcc(_)) => continue,
+            Some(BotMessage::Xdcc { sender, reply }) => {
+                log::debug!("Ignoring reply of {} while waiting for {}: {:?}", sender, bot, reply);
+                continue;
+            }
+            None => return Err(Failure::ConnectionLost.into()),
+        };
+        match reply {
+            XdccReply::InvalidPack => return Err(anyhow!(Failure::PacksFailed { failed: 1, total: 1 })
+                .context(format!("{} does not have pack #{}", bot, pack))),
+            XdccReply::PackInfo(_) => received = true,
+            XdccReply::InfoField(name, value) => {
+                info.set_field(&name, value);
+                received = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("201000"), Some(201000));
+        assert_eq!(parse_size("[195KB]"), Some(195 * 1024));
+        assert_eq!(parse_size("[ 95K]"), Some(95 * 1024));
+        assert_eq!(parse_size("350MB"), Some(350 * 1024 * 1024));
+        assert_eq!(parse_size("350 MiB"), Some(350 * 1024 * 1024));
+        assert_eq!(parse_size("1.5g"), Some(1536 * 1024 * 1024));
+        assert_eq!(parse_size("2T"), Some(2 << 40));
+        assert_eq!(parse_size("12B"), Some(12));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("?"), None);
+        assert_eq!(parse_size("1.2X"), None);
+        assert_eq!(parse_size("G"), None);
+    }
+}
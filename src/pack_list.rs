@@ -0,0 +1,231 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::irc::command::ctcp::dcc::{DccType, PassiveDcc, Send, TransferOptions};
+use crate::irc::command::xdcc::Xdcc;
+use crate::irc::{BotMessage, IrcDccClient};
+use crate::pack_info::parse_size;
+use crate::package_downloader::DownloaderOptions;
+use crate::progress::format_bytes;
+use crate::summary::Failure;
+
+// Pack lists are kept in memory, anything larger is not a pack list
+const MAX_LIST_SIZE: u64 = 16 * 1024 * 1024;
+
+lazy_static! {
+    // e.g. `#1   12x [1.2G] filename` (iroffer) or `#1 [1.2G] filename` (lists without download counts)
+    static ref PACK_REGEX: Regex =
+        Regex::new(r#"^\s*#(\d+)\s+(?:(\d+)x\s+)?\[\s*([^\]]*?)\s*\]\s+(.+?)\s*$"#).unwrap();
+    // bold, color, reset, reverse, italic, strikethrough, monospace and underline codes of IRC clients
+    static ref FORMATTING_REGEX: Regex =
+        Regex::new(r#"\x03(?:\d{1,2}(?:,\d{1,2})?)?|[\x02\x0f\x16\x1d\x1e\x11\x1f]"#).unwrap();
+}
+
+/// How a pack list is printed
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ListFormat {
+    // Aligned columns for reading
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PackEntry {
+    pub pack: u32,
+    // Not every list counts the downloads
+    pub gets: Option<u32>,
+    // Approximate size in bytes, the bots only send rounded sizes
+    pub size: Option<u64>,
+    pub filename: String,
+}
+
+/// The packs offered by a bot
+#[derive(Debug, Clone, Serialize)]
+pub struct PackList {
+    pub bot: String,
+    pub packs: Vec<PackEntry>,
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl PackList {
+    /// Parses the iroffer list format and lists without download counts, also with IRC formatting codes.
+    /// Lines which do not describe a pack are ignored.
+    pub fn parse(bot: &str, text: &str) -> Self {
+        let packs = text.lines()
+            .map(|line| FORMATTING_REGEX.replace_all(line, ""))
+            .filter_map(|line| PACK_REGEX.captures(&line).and_then(|captures| Some(PackEntry {
+                pack: captures[1].parse().ok()?,
+                gets: match captures.get(2) {
+                    Some(gets) => Some(gets.as_str().parse().ok()?),
+                    None => None,
+                },
+                size: parse_size(&captures[3]),
+                filename: captures[4].to_string(),
+            })))
+            .collect();
+        Self { bot: bot.to_string(), packs }
+    }
+
+    /// Keeps only the packs with a filename matching the pattern
+    pub fn filter(mut self, pattern: &Regex) -> Self {
+        self.packs.retain(|entry| pattern.is_match(&entry.filename));
+        self
+    }
+
+    pub fn format(&self, format: ListFormat) -> Result<String> {
+        let output = match format {
+            ListFormat::Text => self.packs.iter()
+                .map(|entry| {
+                    let gets = entry.gets.map(|gets| format!("{}x", gets)).unwrap_or_else(|| "?".to_string());
+                    let size = entry.size.map(format_bytes).unwrap_or_else(|| "?".to_string());
+                    format!("#{:<6} {:>7} {:>10}  {}\n", entry.pack, gets, size, entry.filename)
+                })
+                .collect(),
+            ListFormat::Json => serde_json::to_string(self)? + "\n",
+            ListFormat::Csv => {
+                let mut output = "pack,gets,size,filename\n".to_string();
+                for entry in &self.packs {
+                    let gets = entry.gets.map(|gets| gets.to_string()).unwrap_or_default();
+                    let size = entry.size.map(|size| size.to_string()).unwrap_or_default();
+                    output.push_str(&format!("{},{},{},{}\n", entry.pack, gets, size,
+                                             escape_csv(&entry.filename)));
+                }
+                output
+            }
+        };
+        Ok(output)
+    }
+}
+
+async fn receive_list(client: &mut IrcDccClient, sender: String, send: &Send, passive: Option<&PassiveDcc>,
+                      transfer: &TransferOptions) -> Result<Vec<u8>> {
+    if send.file_size() > MAX_LIST_SIZE {
+        bail!("The pack list {} is too large ({} bytes)", send.filename, send.file_size());
+    }
+    if !send.is_passive() {
+        return send.fetch(transfer).await;
+    }
+    let passive = passive.ok_or_else(|| anyhow!("The bot sent its pack list using passive DCC, \
+        which requires --dcc-ip to be set!"))?;
+    let listener = passive.bind().await?;
+    let port = listener.local_addr()?.port();
+    if let Some(reply) = send.passive_reply(sender, passive, port) {
+        client.send_passive_dcc(reply)?;
+    }
+    send.fetch_passive(listener, passive.accept_timeout, transfer).await
+}
+
+/// Requests the pack list of the bot and receives it over DCC
+pub async fn fetch_pack_list(client: &mut IrcDccClient, bot: &str, options: &DownloaderOptions) -> Result<PackList> {
+    client.send_dcc_request(Xdcc::List(bot.to_string()))?;
+    let timeout_duration = Duration::from_secs(options.timeout_seconds);
+    loop {
+        let message = match timeout(timeout_duration, client.wait_for_bot_message()).await {
+            Ok(message) => message?,
+            Err(_) => return Err(anyhow!(Failure::Timeout { failed: 1, total: 1 })
+                .context(format!("{} did not send its pack list", bot))),
+        };
+        let (sender, send) = match message {
+            Some(BotMessage::Dcc(dcc)) => match dcc.dcc_type {
+                DccType::Send(send) => (dcc.sender, send),
+                DccType::Accept(_) => continue,
+            },
+            Some(BotMessage::Xdcc { .. }) => continue,
+            None => return Err(Failure::ConnectionLost.into()),
+        };
+        if !options.accepts_offer(&sender, &send, std::iter::once(bot)) {
+            continue;
+        }
+        let data = receive_list(client, sender, &send, options.passive.as_ref(), &options.transfer).await?;
+        return Ok(PackList::parse(bot, &String::from_utf8_lossy(&data)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IROFFER_LIST: &str = "\
+** 3 packs **  1 of 2 slots open, Record: 1.2MB/s
+** Bandwidth Usage ** Current: 0.0KB/s, Record: 1.2MB/s
+** To request a file, type \"/msg Bot xdcc send #x\" **
+#1   120x [1.2G] Show - 01 [1080p].mkv
+#2     0x [350M] Show, \"Special\".mkv
+#10    3x [ 95K] notes.txt
+Total Offered: 1.5 GB  Total Transferred: 150 GB
+";
+
+    fn entry(pack: u32, gets: Option<u32>, size: Option<u64>, filename: &str) -> PackEntry {
+        PackEntry { pack, gets, size, filename: filename.to_string() }
+    }
+
+    fn entries(list: &PackList) -> Vec<(u32, Option<u32>, Option<u64>, &str)> {
+        list.packs.iter().map(|entry| (entry.pack, entry.gets, entry.size, entry.filename.as_str())).collect()
+    }
+
+    #[test]
+    fn parses_iroffer_lists() {
+        let list = PackList::parse("Bot", IROFFER_LIST);
+        assert_eq!(list.bot, "Bot");
+        assert_eq!(entries(&list), vec![
+            (1, Some(120), Some(1288490188), "Show - 01 [1080p].mkv"),
+            (2, Some(0), Some(350 * 1024 * 1024), "Show, \"Special\".mkv"),
+            (10, Some(3), Some(95 * 1024), "notes.txt"),
+        ]);
+    }
+
+    #[test]
+    fn parses_other_layouts() {
+        let list = PackList::parse("Bot", "\
+#1 [1.2G] without gets.mkv
+\x02#2\x02  \x0304,01 5x\x03 [\x0312 10M\x03] \x1fcolored.mkv\x0f
+#3 5x [?] unknown size.mkv
+#x 5x [1G] invalid pack.mkv
+#4 99999999999x [1G] too many gets.mkv
+");
+        assert_eq!(entries(&list), vec![
+            (1, None, Some(1288490188), "without gets.mkv"),
+            (2, Some(5), Some(10 * 1024 * 1024), "colored.mkv"),
+            (3, Some(5), None, "unknown size.mkv"),
+        ]);
+    }
+
+    #[test]
+    fn filters_by_filename() {
+        let pattern = Regex::new("(?i)SHOW").unwrap();
+        let list = PackList::parse("Bot", IROFFER_LIST).filter(&pattern);
+        assert_eq!(list.packs.iter().map(|entry| entry.pack).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn exports_csv_and_json() {
+        let list = PackList {
+            bot: "Bot".to_string(),
+            packs: vec![
+                entry(1, Some(12), Some(2048), "Show, \"Special\".mkv"),
+                entry(2, None, None, "file.mkv"),
+            ],
+        };
+        assert_eq!(list.format(ListFormat::Csv).unwrap(),
+                   "pack,gets,size,filename\n1,12,2048,\"Show, \"\"Special\"\".mkv\"\n2,,,file.mkv\n");
+        assert_eq!(list.format(ListFormat::Json).unwrap(), concat!(
+            r#"{"bot":"Bot","packs":[{"pack":1,"gets":12,"size":2048,"filename":"Show, \"Special\".mkv"},"#,
+            r#"{"pack":2,"gets":null,"size":null,"filename":"file.mkv"}]}"#, "\n"));
+        assert_eq!(list.format(ListFormat::Text).unwrap(), concat!(
+            "#1          12x    2.0 KiB  Show, \"Special\".mkv\n",
+            "#2            ?          ?  file.mkv\n"));
+    }
+}
@@ -1,137 +1,900 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::PathBuf;
 use std::time::Duration;
-use anyhow::{Result, bail};
+use anyhow::Result;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::task::JoinHandle;
-use tokio::time::timeout;
-use crate::irc::command::ctcp::dcc::{Dcc, DccType};
-use crate::irc::command::xdcc::Xdcc;
-use crate::irc::IrcDccClient;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{sleep_until, timeout, Instant};
+use crate::irc::command::ctcp::dcc::{Accept, Dcc, DccType, PassiveDcc, Resume, Send, TransferOptions};
+use crate::irc::command::xdcc::{BatchStyle, Xdcc, XdccReply};
+use crate::irc::{BotMessage, IrcDccClient};
+use crate::download_path::{DownloadPaths, DownloadTarget};
+use crate::event::{Event, TransferMonitor};
+use crate::queue_file::StateFile;
+use crate::summary::{Failure, PackResult};
 
 
-fn calculate_total_downloads(command: &Xdcc) -> Result<u32> {
+/// A pack requested from a bot, `pack` is None for the pack list
+#[derive(Debug, Clone)]
+struct PackRequest {
+    bot: String,
+    pack: Option<u32>,
+    // how often the pack was requested again after failing
+    retries: u32,
+    // name of the file in the pack, if the bot told us
+    expected_filename: Option<String>,
+    // position in the queue of the bot, while waiting for a free slot
+    queue_position: Option<u32>,
+    // the last offer of the bot for this pack
+    filename: Option<String>,
+    path: Option<PathBuf>,
+    file_size: u64,
+    // the last attempt failed because the bot did not send the pack
+    timed_out: bool,
+    // position of the pack in the summary, packs are listed in the order they were given
+    order: usize,
+    // the command the pack was requested by
+    command_id: u64,
+    // directory to save the pack to instead of the default output directory
+    output_dir: Option<PathBuf>,
+}
+
+impl PackRequest {
+    fn new(bot: &str, pack: Option<u32>, order: usize) -> Self {
+        Self {
+            bot: bot.to_string(),
+            pack,
+            retries: 0,
+            expected_filename: None,
+            queue_position: None,
+            filename: None,
+            path: None,
+            file_size: 0,
+            timed_out: false,
+            order,
+            command_id: 0,
+            output_dir: None,
+        }
+    }
+
+    /// Whether the bot answered the request yet
+    fn is_acknowledged(&self) -> bool {
+        self.expected_filename.is_some() || self.queue_position.is_some()
+    }
+
+    fn into_result(self, bytes: u64, error: Option<String>) -> PackResult {
+        PackResult {
+            bot: self.bot,
+            pack: self.pack,
+            filename: self.filename,
+            path: self.path,
+            bytes,
+            error,
+            timed_out: self.timed_out,
+        }
+    }
+}
+
+impl fmt::Display for PackRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pack {
+            Some(pack) => write!(f, "pack #{} from {}", pack, self.bot),
+            None => write!(f, "pack from {}", self.bot),
+        }
+    }
+}
+
+fn pack_requests(command: &Xdcc, first_order: usize) -> Result<VecDeque<PackRequest>> {
     match command {
-        Xdcc::Send(_, _) => Ok(1),
-        Xdcc::Batch(_, packages) => Ok(packages.len() as u32),
-        _ => bail!("Only SEND and BATCH are supported!"),
+        Xdcc::Send(bot, package) => Ok(VecDeque::from([PackRequest::new(bot, *package, first_order)])),
+        // the pack list is saved like any other file
+        Xdcc::List(bot) => Ok(VecDeque::from([PackRequest::new(bot, None, first_order)])),
+        Xdcc::Batch(bot, packages) => Ok(packages.iter().enumerate()
+            .map(|(i, p)| PackRequest::new(bot, Some(*p), first_order + i))
+            .collect()),
+        _ => Err(Failure::BadCommand("Only SEND and BATCH are supported!".to_string()).into()),
+    }
+}
+
+/// Fails if the downloader cannot run the command
+pub fn validate_command(command: &Xdcc) -> Result<()> {
+    pack_requests(command, 0).map(|_| ())
+}
+
+/// A command to download, with the directory for its packs if it differs from the default
+pub struct DownloadCommand {
+    pub command: Xdcc,
+    pub output_dir: Option<PathBuf>,
+}
+
+impl From<Xdcc> for DownloadCommand {
+    fn from(value: Xdcc) -> Self {
+        Self {
+            command: value,
+            output_dir: None,
+        }
+    }
+}
+
+/// A command waiting for the packs of the previous command to the same bot
+struct QueuedCommand {
+    id: u64,
+    command: Xdcc,
+    requests: VecDeque<PackRequest>,
+}
+
+/// Changes to the commands of a running downloader
+pub enum Control {
+    // Download the command, the id must be unique
+    Add(u64, DownloadCommand),
+    // Stop downloading the packs of the command
+    Cancel(u64),
+    // Do not send further commands to the bots, running downloads continue
+    Pause,
+    Resume,
+}
+
+/// Lets the daemon change the commands while the downloader runs, it keeps running until `control` is closed
+pub struct ControlLink {
+    pub control: UnboundedReceiver<Control>,
+    // Receives the results of every finished command
+    pub finished: UnboundedSender<(u64, Vec<PackResult>)>,
+}
+
+struct FinishedPack {
+    order: usize,
+    command_id: u64,
+    result: PackResult,
+}
+
+struct PendingResume {
+    request: PackRequest,
+    sender: String,
+    send: Send,
+    path: PathBuf,
+    position: u64,
+}
+
+/// Where a cancelled pack was, which decides how to withdraw it from the bot
+enum CancelledState {
+    // Requested from the bot, which may have queued it
+    Requested,
+    // Offered or being sent by the bot
+    Sending,
+    // Waiting for a retry, the bot does not know about it
+    Scheduled,
+}
+
+/// Settings for downloading the packs of a command
+pub struct DownloaderOptions {
+    pub timeout_seconds: u64,
+    pub passive: Option<PassiveDcc>,
+    pub transfer: TransferOptions,
+    pub paths: DownloadPaths,
+    // How often a failed pack is requested again
+    pub max_retries: u32,
+    // Delay before the first retry, doubled for every further retry
+    pub retry_delay: Duration,
+    // How often to reconnect after the connection was closed, waiting like for retries
+    pub max_reconnects: u32,
+    // How many bots to download from at the same time, None for all of them
+    pub max_bots: Option<usize>,
+    // Nicknames allowed to send packs in addition to the bots we requested them from
+    pub allowed_senders: Vec<String>,
+    // Accept offers pointing to private or loopback addresses
+    pub allow_private_endpoints: bool,
+    pub batch_style: BatchStyle,
+    // Records downloaded packs, so they are skipped when running the same queue again
+    pub state: Option<StateFile>,
+}
+
+impl DownloaderOptions {
+    /// Whether `sender` may send the packs requested from `bot`, nicknames are case insensitive
+    fn is_allowed_sender(&self, sender: &str, bot: &str) -> bool {
+        sender.eq_ignore_ascii_case(bot)
+            || self.allowed_senders.iter().any(|allowed| allowed.eq_ignore_ascii_case(sender))
+    }
+
+    /// Checks an offer against the bots we requested packs from, --dcc-allow-sender and --dcc-allow-private,
+    /// logging why it is rejected
+    pub fn accepts_offer<'a>(&self, sender: &str, send: &Send, mut bots: impl Iterator<Item = &'a str>) -> bool {
+        let reason = if !bots.any(|bot| self.is_allowed_sender(sender, bot)) {
+            "no pack was requested from this sender".to_string()
+        } else if send.has_private_endpoint() && !self.allow_private_endpoints {
+            format!("{} is a private address, use --dcc-allow-private to accept it", send.ip())
+        } else {
+            return true;
+        };
+        log::warn!("Rejecting DCC SEND of {} from {}: {}", send.filename, sender, reason);
+        false
+    }
+}
+
+enum Activity {
+    Message(Option<BotMessage>),
+    Control(Option<Control>),
+    TransferDone(usize, Result<()>),
+    // a cancelled transfer stopped
+    TransferAborted,
+    RetryDue,
+    Timeout,
+}
+
+/// Compares file names the way bots change them between their notices and offers,
+/// e.g. iroffer replaces spaces with underscores
+fn same_filename(announced: &str, offered: &str) -> bool {
+    let normalize = |filename: &str| filename.trim_matches('"').replace(' ', "_").to_lowercase();
+    normalize(announced) == normalize(offered)
+}
+
+async fn next_control(link: &mut Option<ControlLink>) -> Option<Control> {
+    match link {
+        Some(link) => link.control.recv().await,
+        None => std::future::pending().await,
     }
 }
 
 pub struct PackageDownloader {
     client: IrcDccClient,
-    downloads: Vec<JoinHandle<Result<()>>>,
-    total_downloads: u32,
-    handled_downloads: u32,
+    downloads: JoinSet<(usize, Result<()>)>,
+    // packs of the running downloads, by transfer id
+    active: HashMap<usize, PackRequest>,
+    abort_handles: HashMap<usize, AbortHandle>,
+    pending_resumes: Vec<PendingResume>,
+    // packs we expect offers for, bots send them in the requested order
+    awaiting: VecDeque<PackRequest>,
+    scheduled_retries: Vec<(Instant, PackRequest)>,
+    // commands not sent yet, by lowercase bot nickname, every bot gets one command at a time
+    queued_commands: HashMap<String, VecDeque<QueuedCommand>>,
+    results: Vec<FinishedPack>,
+    // number of packs of every command which are not finished yet
+    unfinished_packs: HashMap<u64, usize>,
+    next_order: usize,
+    paused: bool,
+    link: Option<ControlLink>,
+    connection_lost: bool,
+    next_transfer_id: usize,
     quit_on_next_timeout: bool,
     timeout_duration: Duration,
-    finished_sender: UnboundedSender<String>,
-    print_handle: JoinHandle<()>,
+    options: DownloaderOptions,
+    event_sender: UnboundedSender<Event>,
 }
 
 impl PackageDownloader {
-    pub async fn new(mut client: IrcDccClient, command: Xdcc, timeout_seconds: u64) -> Result<Self> {
-        let total_downloads = calculate_total_downloads(&command)?;
-        client.send_dcc_request(command.clone())?;
-
-        let (finished_sender, finished_receiver) = tokio::sync::mpsc::unbounded_channel();
-        let print_handle = tokio::spawn(PackageDownloader::print_finished_downloads(
-            finished_receiver, total_downloads));
-
-        Ok(Self {
+    pub async fn new(client: IrcDccClient, commands: Vec<DownloadCommand>, options: DownloaderOptions,
+                     event_sender: UnboundedSender<Event>) -> Result<Self> {
+        let mut downloader = Self {
             client,
-            downloads: Vec::new(),
-            total_downloads,
-            handled_downloads: 0,
+            downloads: JoinSet::new(),
+            active: HashMap::new(),
+            abort_handles: HashMap::new(),
+            pending_resumes: Vec::new(),
+            awaiting: VecDeque::new(),
+            scheduled_retries: Vec::new(),
+            queued_commands: HashMap::new(),
+            results: Vec::new(),
+            unfinished_packs: HashMap::new(),
+            next_order: 0,
+            paused: false,
+            link: None,
+            connection_lost: false,
+            next_transfer_id: 0,
             quit_on_next_timeout: false,
-            timeout_duration: Duration::from_secs(timeout_seconds / 2),
-            finished_sender,
-            print_handle,
-        })
+            timeout_duration: Duration::from_secs(options.timeout_seconds / 2),
+            options,
+            event_sender,
+        };
+        let mut bots = Vec::new();
+        for (id, command) in commands.into_iter().enumerate() {
+            let bot = downloader.queue_command(id as u64, command)?;
+            if !bots.contains(&bot) {
+                bots.push(bot);
+            }
+        }
+        // the bots handle their queues independently, so all of them get their first command right away
+        for bot in bots {
+            // cancel previous transfers before starting new ones
+            downloader.client.send_dcc_request(Xdcc::Remove(bot.clone(), None))?;
+            downloader.client.send_dcc_request(Xdcc::Cancel(bot.clone()))?;
+            downloader.send_next_command(&bot)?;
+        }
+        Ok(downloader)
     }
 
+    /// Keeps the downloader running and lets the link change its commands
+    pub fn with_control(mut self, link: ControlLink) -> Self {
+        self.link = Some(link);
+        self
+    }
 
-    async fn print_finished_downloads(mut finished_receiver: UnboundedReceiver<String>,
-                                      total_downloads: u32) {
-        let mut handled_downloads = 0u32;
-        loop {
-            if handled_downloads == total_downloads {
-                finished_receiver.close();
-                return;
+    /// Adds the command to the queue of its bot, returns the lowercase nickname of the bot
+    fn queue_command(&mut self, id: u64, command: DownloadCommand) -> Result<String> {
+        let DownloadCommand { command, output_dir } = command;
+        let mut requests = pack_requests(&command, self.next_order)?;
+        self.next_order += requests.len();
+        for request in &mut requests {
+            request.command_id = id;
+            request.output_dir = output_dir.clone();
+        }
+        self.unfinished_packs.insert(id, requests.len());
+        let bot = command.recipient().to_lowercase();
+        self.queued_commands.entry(bot.clone()).or_default().push_back(QueuedCommand { id, command, requests });
+        Ok(bot)
+    }
+
+    fn send_command(&mut self, queued: QueuedCommand) -> Result<()> {
+        let QueuedCommand { command, requests, .. } = queued;
+        for request in command.requests(self.options.batch_style) {
+            self.client.send_dcc_request(request)?;
+        }
+        self.event_sender.send(Event::Requested {
+            bot: command.recipient().to_string(),
+            packs: requests.iter().filter_map(|request| request.pack).collect(),
+        })?;
+        self.awaiting.extend(requests);
+        Ok(())
+    }
+
+    /// Whether any pack of the bot is not finished yet
+    fn has_unfinished_packs(&self, bot: &str) -> bool {
+        let from_bot = |request: &PackRequest| request.bot.eq_ignore_ascii_case(bot);
+        self.awaiting.iter().any(from_bot)
+            || self.active.values().any(from_bot)
+            || self.pending_resumes.iter().any(|resume| from_bot(&resume.request))
+            || self.scheduled_retries.iter().any(|(_, request)| from_bot(request))
+    }
+
+    /// Number of bots with packs which are not finished yet
+    fn busy_bots(&self) -> usize {
+        let bots = self.awaiting.iter()
+            .chain(self.active.values())
+            .chain(self.pending_resumes.iter().map(|resume| &resume.request))
+            .chain(self.scheduled_retries.iter().map(|(_, request)| request))
+            .map(|request| request.bot.to_lowercase())
+            .collect::<HashSet<_>>();
+        bots.len()
+    }
+
+    /// Sends the next queued commands to the idle bots, the bots with the oldest commands first
+    fn send_next_commands(&mut self) -> Result<()> {
+        let mut bots = self.queued_commands.iter()
+            .filter_map(|(bot, queue)| {
+                let order = queue.front()?.requests.front().map_or(usize::MAX, |request| request.order);
+                Some((order, bot.clone()))
+            })
+            .collect::<Vec<_>>();
+        bots.sort();
+        bots.iter().try_for_each(|(_, bot)| self.send_next_command(bot))
+    }
+
+    /// Sends the next queued command to the bot once all packs of the previous one are finished
+    fn send_next_command(&mut self, bot: &str) -> Result<()> {
+        if self.connection_lost || self.paused || self.has_unfinished_packs(bot) {
+            return Ok(());
+        }
+        if self.options.max_bots.is_some_and(|max_bots| self.busy_bots() >= max_bots) {
+            return Ok(());
+        }
+        let next = self.queued_commands.get_mut(&bot.to_lowercase()).and_then(VecDeque::pop_front);
+        match next {
+            Some(queued) => self.send_command(queued),
+            None => Ok(()),
+        }
+    }
+
+    fn finish_pack(&mut self, request: PackRequest, bytes: u64, error: Option<String>) -> Result<()> {
+        let bot = request.bot.clone();
+        if let (Some(state), None) = (&mut self.options.state, &error) {
+            if let Err(e) = state.mark_done(&bot, request.pack) {
+                log::warn!("Failed to mark {} as done: {:?}", request, e);
+            }
+        }
+        let command_id = request.command_id;
+        self.results.push(FinishedPack {
+            order: request.order,
+            command_id,
+            result: request.into_result(bytes, error),
+        });
+        if let Some(unfinished) = self.unfinished_packs.get_mut(&command_id) {
+            *unfinished = unfinished.saturating_sub(1);
+            if *unfinished == 0 {
+                self.unfinished_packs.remove(&command_id);
+                self.report_finished_command(command_id)?;
+            }
+        }
+        // with --concurrency, other bots may be waiting for this one to finish
+        self.send_next_commands()
+    }
+
+    fn report_finished_command(&mut self, command_id: u64) -> Result<()> {
+        let link = match &self.link {
+            Some(link) => link,
+            None => return Ok(()),
+        };
+        // the daemon keeps track of the results itself
+        let (finished, results) = std::mem::take(&mut self.results).into_iter()
+            .partition::<Vec<_>, _>(|pack| pack.command_id == command_id);
+        self.results = results;
+        let finished = finished.into_iter().map(|pack| pack.result).collect();
+        // the daemon may be shutting down
+        let _ = link.finished.send((command_id, finished));
+        Ok(())
+    }
+
+    /// Removes a pack of the command which is not finished, stopping its download if it is running
+    fn take_command_request(&mut self, command_id: u64) -> Option<(PackRequest, CancelledState)> {
+        if let Some(index) = self.awaiting.iter().position(|request| request.command_id == command_id) {
+            return Some((self.awaiting.remove(index)?, CancelledState::Requested));
+        }
+        if let Some(index) = self.pending_resumes.iter().position(|resume| resume.request.command_id == command_id) {
+            return Some((self.pending_resumes.remove(index).request, CancelledState::Sending));
+        }
+        if let Some(index) = self.scheduled_retries.iter().position(|(_, request)| request.command_id == command_id) {
+            return Some((self.scheduled_retries.remove(index).1, CancelledState::Scheduled));
+        }
+        let id = self.active.iter().find(|(_, request)| request.command_id == command_id).map(|(id, _)| *id)?;
+        if let Some(handle) = self.abort_handles.remove(&id) {
+            handle.abort();
+        }
+        TransferMonitor::new(id, self.event_sender.clone()).failed("Cancelled".to_string());
+        Some((self.active.remove(&id)?, CancelledState::Sending))
+    }
+
+    async fn cancel_command(&mut self, command_id: u64) -> Result<()> {
+        for queue in self.queued_commands.values_mut() {
+            queue.retain(|queued| queued.id != command_id);
+        }
+        while let Some((request, state)) = self.take_command_request(command_id) {
+            log::info!("Cancelling {}.", request);
+            match (state, request.pack) {
+                // `xdcc remove` only removes packs from the queue of the bot
+                (CancelledState::Requested, Some(pack)) => {
+                    self.client.send_dcc_request(Xdcc::Remove(request.bot.clone(), Some(pack)))?
+                }
+                (CancelledState::Sending, _) => self.client.send_dcc_request(Xdcc::Cancel(request.bot.clone()))?,
+                _ => {}
+            }
+            self.give_up(request, "Cancelled".to_string()).await?;
+        }
+        // commands which were not sent yet have no requests to give up
+        if self.unfinished_packs.remove(&command_id).is_some() {
+            self.report_finished_command(command_id)?;
+        }
+        Ok(())
+    }
+
+    async fn handle_control(&mut self, control: Option<Control>) -> Result<()> {
+        match control {
+            Some(Control::Add(id, command)) if !self.connection_lost => {
+                let bot = self.queue_command(id, command)?;
+                self.send_next_command(&bot)
+            }
+            Some(Control::Add(id, _)) => {
+                log::warn!("Not adding command {}, the connection is closed.", id);
+                Ok(())
+            }
+            Some(Control::Cancel(id)) => self.cancel_command(id).await,
+            Some(Control::Pause) => {
+                self.paused = true;
+                Ok(())
+            }
+            Some(Control::Resume) => {
+                self.paused = false;
+                self.send_next_commands()
+            }
+            None => {
+                self.link = None;
+                Ok(())
             }
-            let filename = match finished_receiver.recv().await {
-                Some(message) => message,
-                None => return,
-            };
-            println!("{}", filename);
-            handled_downloads += 1;
         }
     }
 
+
+    fn monitor(&mut self) -> TransferMonitor {
+        let id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+        TransferMonitor::new(id, self.event_sender.clone())
+    }
+
+    fn report_failure(monitor: &TransferMonitor, result: Result<()>) -> Result<()> {
+        if let Err(e) = &result {
+            monitor.failed(e.to_string());
+        }
+        result
+    }
+
+    fn is_done(&self) -> bool {
+        self.awaiting.is_empty() && self.pending_resumes.is_empty() && self.active.is_empty()
+            && self.scheduled_retries.is_empty() && self.queued_commands.values().all(VecDeque::is_empty)
+            && (self.link.is_none() || self.connection_lost)
+    }
+
     async fn handle_timeout(&mut self) -> Result<()> {
-        if self.downloads.iter().any(|f| !f.is_finished()) {
+        // the bot did not answer our resume requests, start these downloads from scratch
+        for resume in std::mem::take(&mut self.pending_resumes) {
+            log::warn!("{} did not accept resuming {}, restarting download.",
+                resume.sender, resume.path.display());
+            self.handle_download(resume.request, resume.sender, resume.send, resume.path, 0).await?;
+        }
+
+        // queued packs can take a long time to be sent, so they never time out
+        let waiting = self.awaiting.iter().any(|request| request.queue_position.is_none());
+        if !self.active.is_empty() || !waiting {
             Ok(())
         } else if self.quit_on_next_timeout {
-            self.client.quit().await?;
-            bail!("Timed out waiting for packages!")
+            self.quit_on_next_timeout = false;
+            let (queued, timed_out) = std::mem::take(&mut self.awaiting).into_iter()
+                .partition::<VecDeque<_>, _>(|request| request.queue_position.is_some());
+            self.awaiting = queued;
+            for mut request in timed_out {
+                request.timed_out = true;
+                self.handle_failure(request, "Timed out waiting for the bot to send the pack".to_string()).await?;
+            }
+            Ok(())
         } else {
             self.quit_on_next_timeout = true;
             Ok(())
         }
     }
 
-    async fn handle_download(&mut self, dcc: Dcc) -> Result<()> {
-        let DccType::Send(send) = dcc.dcc_type;
-        let normalized_filename = send.normalized_filename();
-        log::info!("Accepting download of {} from {}.", normalized_filename, dcc.sender);
-        let download_finished_sender = self.finished_sender.clone();
-        let download = tokio::spawn(async move {
-            send.start_download().await?;
-            download_finished_sender.send(normalized_filename)?;
-            Ok(())
-        });
-        self.downloads.push(download);
+    async fn give_up(&mut self, request: PackRequest, reason: String) -> Result<()> {
+        log::info!("Giving up on {}: {}", request, reason);
+        self.event_sender.send(Event::PackFailed {
+            bot: request.bot.clone(),
+            pack: request.pack,
+            reason: reason.clone(),
+        })?;
+        // keep track of what we got so far, the file can be resumed later
+        let bytes = match &request.path {
+            Some(path) => tokio::fs::metadata(path).await.map(|metadata| metadata.len()).unwrap_or_default(),
+            None => 0,
+        };
+        self.finish_pack(request, bytes, Some(reason))
+    }
+
+    async fn handle_failure(&mut self, mut request: PackRequest, reason: String) -> Result<()> {
+        if request.retries >= self.options.max_retries || self.connection_lost {
+            return self.give_up(request, reason).await;
+        }
+
+        // wait twice as long after every failed attempt
+        let delay = self.options.retry_delay.saturating_mul(1 << request.retries.min(16));
+        request.retries += 1;
+        log::info!("Requesting {} again in {:?} (retry {} of {}): {}",
+            request, delay, request.retries, self.options.max_retries, reason);
+        self.event_sender.send(Event::Retrying {
+            bot: request.bot.clone(),
+            pack: request.pack,
+            retry: request.retries,
+            delay_seconds: delay.as_secs_f64(),
+        })?;
+        self.scheduled_retries.push((Instant::now() + delay, request));
+        Ok(())
+    }
+
+    fn send_due_retries(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.scheduled_retries).into_iter()
+            .partition::<Vec<_>, _>(|(at, _)| *at <= now);
+        self.scheduled_retries = waiting;
+        for (_, request) in due {
+            self.client.send_dcc_request(Xdcc::Send(request.bot.clone(), request.pack))?;
+            self.awaiting.push_back(request);
+        }
+        self.quit_on_next_timeout = false;
+        Ok(())
+    }
+
+    async fn handle_transfer_done(&mut self, id: usize, result: Result<()>) -> Result<()> {
+        self.abort_handles.remove(&id);
+        let request = match self.active.remove(&id) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        match result {
+            Ok(()) => {
+                let bytes = request.file_size;
+                self.finish_pack(request, bytes, None)
+            }
+            Err(e) => self.handle_failure(request, e.to_string()).await,
+        }
+    }
+
+    /// Connects again with increasing delays, gives up on all packs which did not start yet if it fails
+    async fn handle_disconnect(&mut self) -> Result<()> {
+        for attempt in 1..=self.options.max_reconnects {
+            let delay = self.options.retry_delay.saturating_mul(1 << (attempt - 1).min(16));
+            log::info!("Connection closed, reconnecting in {:?} (attempt {} of {}).",
+                delay, attempt, self.options.max_reconnects);
+            self.event_sender.send(Event::Reconnecting { attempt, delay_seconds: delay.as_secs_f64() })?;
+            tokio::time::sleep(delay).await;
+            let reconnect_timeout = Duration::from_secs(self.options.timeout_seconds);
+            match timeout(reconnect_timeout, self.client.reconnect()).await {
+                Ok(Ok(())) => return self.handle_reconnect(),
+                Ok(Err(e)) => log::warn!("Failed to reconnect: {:?}", e),
+                Err(_) => log::warn!("Timed out reconnecting to {}.", self.client.server()),
+            }
+        }
+        self.handle_connection_lost().await
+    }
+
+    /// Requests the packs which did not start yet again, the bots forgot about them with the old connection
+    fn handle_reconnect(&mut self) -> Result<()> {
+        self.event_sender.send(Event::Connected { server: self.client.server().to_string() })?;
+        for channel in self.client.channels() {
+            self.event_sender.send(Event::Joined { channel: channel.clone() })?;
+        }
+        // the bots cannot accept our resume requests anymore, the offers will come again
+        let resumes = std::mem::take(&mut self.pending_resumes).into_iter().map(|resume| resume.request);
+        self.awaiting.extend(resumes);
+        for request in self.awaiting.iter_mut() {
+            request.expected_filename = None;
+            request.queue_position = None;
+            self.client.send_dcc_request(Xdcc::Send(request.bot.clone(), request.pack))?;
+        }
+        self.quit_on_next_timeout = false;
         Ok(())
     }
 
-    async fn wait_for_download_completion(mut self) -> Result<()> {
-        for download in self.downloads {
-            let _ = download.await?;
+    async fn handle_connection_lost(&mut self) -> Result<()> {
+        log::warn!("Connection closed, waiting for running downloads to finish.");
+        self.connection_lost = true;
+        let requests = std::mem::take(&mut self.awaiting).into_iter()
+            .chain(std::mem::take(&mut self.pending_resumes).into_iter().map(|resume| resume.request))
+            .chain(std::mem::take(&mut self.scheduled_retries).into_iter().map(|(_, request)| request))
+            .chain(std::mem::take(&mut self.queued_commands).into_values().flatten()
+                .flat_map(|queued| queued.requests))
+            .collect::<Vec<_>>();
+        for request in requests {
+            self.give_up(request, "Connection closed".to_string()).await?;
         }
-        self.client.quit().await?;
-        self.print_handle.await?;
         Ok(())
     }
 
-    async fn wait_for_dcc_with_timeout(&mut self) -> Result<Option<Dcc>> {
-        let result = match timeout(self.timeout_duration, self.client.wait_for_dcc()).await {
-            Ok(result) => result,
-            Err(_) => {
-                self.handle_timeout().await?;
-                return Ok(None);
+    /// Finds the first request to `sender` matching the pack, or which the bot did not answer yet
+    fn find_awaiting(&self, sender: &str, pack: Option<u32>) -> Option<usize> {
+        let from_sender = |request: &PackRequest| request.bot.eq_ignore_ascii_case(sender);
+        match pack {
+            Some(pack) => self.awaiting.iter().position(|request| from_sender(request) && request.pack == Some(pack)),
+            None => self.awaiting.iter().position(|request| from_sender(request) && !request.is_acknowledged()),
+        }
+    }
+
+    async fn handle_reply(&mut self, sender: String, reply: XdccReply) -> Result<()> {
+        match reply {
+            XdccReply::Sending { pack, filename } => {
+                if let Some(index) = self.find_awaiting(&sender, Some(pack)) {
+                    let request = &mut self.awaiting[index];
+                    request.expected_filename = Some(filename);
+                    request.queue_position = None;
+                }
+            }
+            XdccReply::Queued { pack, position, total } => {
+                // bots without pack numbers in queue updates only queue one pack per user
+                let index = match pack {
+                    Some(_) => self.find_awaiting(&sender, pack),
+                    None => self.awaiting.iter().position(|request| request.bot.eq_ignore_ascii_case(&sender)
+                        && request.queue_position.is_some()).or_else(|| self.find_awaiting(&sender, None)),
+                };
+                if let Some(index) = index {
+                    let request = &mut self.awaiting[index];
+                    log::info!("Queued for {} at position {}.", request, position);
+                    request.queue_position = Some(position);
+                    self.event_sender.send(Event::Queued {
+                        bot: request.bot.clone(),
+                        pack: request.pack,
+                        position,
+                        total,
+                    })?;
+                }
             }
+            XdccReply::InvalidPack => {
+                if let Some(request) = self.find_awaiting(&sender, None).and_then(|i| self.awaiting.remove(i)) {
+                    // retrying will not make the pack exist
+                    self.give_up(request, "The bot does not have this pack".to_string()).await?;
+                }
+            }
+            XdccReply::AlreadyRequested => log::info!("{} says the pack was already requested.", sender),
+            // the downloader does not request pack info, offers are matched by the notices of the bot
+            XdccReply::PackInfo(pack) => log::debug!("{} sent the info of pack #{}.", sender, pack),
+            XdccReply::InfoField(name, value) => log::debug!("{} sent pack info {}: {}", sender, name, value),
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, message: BotMessage) -> Result<()> {
+        match message {
+            BotMessage::Dcc(dcc) => self.handle_dcc(dcc).await,
+            BotMessage::Xdcc { sender, reply } => self.handle_reply(sender, reply).await,
+        }
+    }
+
+    async fn handle_dcc(&mut self, dcc: Dcc) -> Result<()> {
+        match dcc.dcc_type {
+            DccType::Send(send) => self.handle_offer(dcc.sender, send).await,
+            DccType::Accept(accept) => self.handle_accept(dcc.sender, accept).await,
+        }
+    }
+
+    /// Finds the request an offer belongs to, preferring packs with a matching file name
+    fn take_request(&mut self, sender: &str, filename: &str) -> Option<PackRequest> {
+        let options = &self.options;
+        let from_sender = |request: &PackRequest| options.is_allowed_sender(sender, &request.bot);
+        let expects = |request: &PackRequest| request.expected_filename.as_deref()
+            .is_some_and(|expected| same_filename(expected, filename));
+        // bots may announce another name than they send, then the oldest request is the best guess
+        let index = self.awaiting.iter().position(|request| from_sender(request) && expects(request))
+            .or_else(|| self.awaiting.iter()
+                .position(|request| from_sender(request) && request.expected_filename.is_none()))
+            .or_else(|| self.awaiting.iter().position(from_sender))?;
+        self.awaiting.remove(index)
+    }
+
+    async fn handle_offer(&mut self, sender: String, send: Send) -> Result<()> {
+        let bots = self.awaiting.iter().map(|request| request.bot.as_str());
+        if !self.options.accepts_offer(&sender, &send, bots) {
+            return Ok(());
+        }
+        let mut request = match self.take_request(&sender, &send.filename) {
+            Some(request) => request,
+            None => return Ok(()),
         };
+        self.event_sender.send(Event::Offer {
+            bot: sender.clone(),
+            filename: send.filename.clone(),
+            size: send.file_size(),
+            ip: send.ip(),
+            port: send.port(),
+        })?;
+        if send.is_passive() && self.options.passive.is_none() {
+            // requesting the pack again would only get the same offer
+            let reason = format!("{} offered {} using passive DCC, which requires --dcc-ip to be set!",
+                                 sender, send.filename);
+            return self.give_up(request, reason).await;
+        }
 
-        let dcc = match result? {
-            Some(dcc) => dcc,
+        request.filename = Some(send.filename.clone());
+        request.file_size = send.file_size();
+        request.timed_out = false;
+        request.queue_position = None;
+        let paths = match &request.output_dir {
+            Some(output_dir) => self.options.paths.with_output_dir(output_dir.clone()),
+            None => self.options.paths.clone(),
+        };
+        let path = paths.expand(&sender, request.pack, &send.normalized_filename())?;
+        let target = paths.resolve(path, send.file_size()).await?;
+        request.path = match &target {
+            DownloadTarget::Download(path, _) | DownloadTarget::Skip(path) => Some(path.clone()),
+        };
+        match target {
+            DownloadTarget::Skip(path) => {
+                log::info!("Skipping download of {}, the file already exists.", path.display());
+                self.monitor().skipped(path);
+                self.finish_pack(request, send.file_size(), None)
+            }
+            DownloadTarget::Download(path, position) if position > 0 => {
+                log::info!("Found partial download of {} ({} of {} bytes), requesting resume.",
+                    path.display(), position, send.file_size());
+                self.client.send_dcc_resume(Resume {
+                    recipient: sender.clone(),
+                    filename: send.filename.clone(),
+                    port: send.port(),
+                    position,
+                    token: send.token(),
+                })?;
+                self.pending_resumes.push(PendingResume { request, sender, send, path, position });
+                Ok(())
+            }
+            DownloadTarget::Download(path, _) => self.handle_download(request, sender, send, path, 0).await,
+        }
+    }
+
+    async fn handle_accept(&mut self, sender: String, accept: Accept) -> Result<()> {
+        let index = self.pending_resumes.iter().position(|resume| resume.sender == sender
+            && resume.send.port() == accept.port && resume.send.token() == accept.token);
+        let resume = match index {
+            Some(index) => self.pending_resumes.remove(index),
             None => {
-                self.client.quit().await?;
-                bail!("Connection closed before all packages were downloaded!")
+                log::warn!("Ignoring DCC ACCEPT for unknown transfer {} from {}.", accept.filename, sender);
+                return Ok(());
             }
         };
+        if accept.position != resume.position {
+            log::warn!("{} accepted resuming {} at byte {} instead of {}.",
+                sender, accept.filename, accept.position, resume.position);
+        }
+        self.handle_download(resume.request, resume.sender, resume.send, resume.path, accept.position).await
+    }
 
-        self.quit_on_next_timeout = false;
-        Ok(Some(dcc))
+    async fn handle_download(&mut self, request: PackRequest, sender: String, send: Send, path: PathBuf,
+                             position: u64) -> Result<()> {
+        log::info!("Accepting download of {} from {}.", path.display(), sender);
+        let monitor = self.monitor();
+        let id = monitor.id();
+        let transfer = self.options.transfer.clone();
+        let handle = match &self.options.passive {
+            Some(passive) if send.is_passive() => {
+                let listener = match passive.bind().await {
+                    Ok(listener) => listener,
+                    Err(e) => return self.handle_failure(request, e.to_string()).await,
+                };
+                let port = listener.local_addr()?.port();
+                if let Some(reply) = send.passive_reply(sender, passive, port) {
+                    self.client.send_passive_dcc(reply)?;
+                }
+                let accept_timeout = passive.accept_timeout;
+                self.downloads.spawn(async move {
+                    let result = send.start_passive_download(listener, &path, position, accept_timeout,
+                                                             &transfer, &monitor).await;
+                    (id, PackageDownloader::report_failure(&monitor, result))
+                })
+            }
+            _ => {
+                self.downloads.spawn(async move {
+                    let result = send.start_download(&path, position, &transfer, &monitor).await;
+                    (id, PackageDownloader::report_failure(&monitor, result))
+                })
+            }
+        };
+        self.abort_handles.insert(id, handle);
+        self.active.insert(id, request);
+        Ok(())
+    }
+
+    async fn next_activity(&mut self) -> Result<Activity> {
+        let next_retry = self.scheduled_retries.iter().map(|(at, _)| *at).min();
+        let connected = !self.connection_lost;
+        let linked = self.link.is_some();
+        tokio::select! {
+            result = timeout(self.timeout_duration, self.client.wait_for_bot_message()), if connected => match result {
+                Ok(message) => Ok(Activity::Message(message?)),
+                Err(_) => Ok(Activity::Timeout),
+            },
+            control = next_control(&mut self.link), if linked => Ok(Activity::Control(control)),
+            Some(result) = self.downloads.join_next() => match result {
+                Ok((id, result)) => Ok(Activity::TransferDone(id, result)),
+                Err(e) if e.is_cancelled() => Ok(Activity::TransferAborted),
+                Err(e) => Err(e.into()),
+            },
+            _ = sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {
+                Ok(Activity::RetryDue)
+            }
+        }
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        if !self.connection_lost {
+            self.client.quit().await?;
+        }
+        self.results.sort_by_key(|pack| pack.order);
+        let results = self.results.into_iter().map(|pack| pack.result).collect::<Vec<_>>();
+        let failure = Failure::from_results(&results)
+            .map(|failure| if self.connection_lost { Failure::ConnectionLost } else { failure });
+        self.event_sender.send(Event::Summary { results })?;
+        match failure {
+            Some(failure) => Err(failure.into()),
+            None => Ok(()),
+        }
     }
 
     pub async fn download_packages(mut self) -> Result<()> {
         loop {
-            if self.handled_downloads == self.total_downloads {
-                self.wait_for_download_completion().await?;
-                return Ok(())
+            if self.is_done() {
+                return self.finish().await;
             }
 
-            if let Some(dcc) = self.wait_for_dcc_with_timeout().await? {
-                self.handle_download(dcc).await?;
-                self.handled_downloads += 1;
+            match self.next_activity().await? {
+                Activity::Message(Some(message)) => {
+                    self.quit_on_next_timeout = false;
+                    self.handle_message(message).await?;
+                }
+                Activity::Message(None) => self.handle_disconnect().await?,
+                Activity::Control(control) => self.handle_control(control).await?,
+                Activity::TransferDone(id, result) => self.handle_transfer_done(id, result).await?,
+                Activity::TransferAborted => {}
+                Activity::RetryDue => self.send_due_retries()?,
+                Activity::Timeout => self.handle_timeout().await?,
             }
         }
     }
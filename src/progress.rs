@@ -0,0 +1,275 @@
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::event::{Event, TransferEvent};
+use crate::pack_info::PackInfo;
+use crate::summary::PackResult;
+
+const BAR_WIDTH: usize = 20;
+const NAME_WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{:02}:{:02}", minutes, seconds)
+    }
+}
+
+fn format_pack(pack: Option<u32>) -> String {
+    pack.map(|pack| format!("pack #{}", pack)).unwrap_or_else(|| "pack".to_string())
+}
+
+fn format_summary(results: &[PackResult]) -> String {
+    let succeeded = results.iter().filter(|result| result.succeeded()).count();
+    let mut summary = format!("Downloaded {} of {} packages:\n", succeeded, results.len());
+    // packs of different bots can have the same number
+    let several_bots = results.iter().any(|result| !result.bot.eq_ignore_ascii_case(&results[0].bot));
+    let packs = results.iter()
+        .map(|result| {
+            let pack = result.pack.map(|pack| format!("#{}", pack)).unwrap_or_else(|| "-".to_string());
+            if several_bots { format!("{} {}", result.bot, pack) } else { pack }
+        })
+        .collect::<Vec<_>>();
+    let pack_width = packs.iter().map(String::len).max().unwrap_or_default().max(6);
+    for (result, pack) in results.iter().zip(packs) {
+        let status = match &result.error {
+            Some(error) => format!("failed: {}", error),
+            None => "ok".to_string(),
+        };
+        summary.push_str(&format!("  {:<pack_width$} {:<width$} {:>10}  {}\n", pack,
+                                  result.filename.as_deref().unwrap_or("-"), format_bytes(result.bytes), status,
+                                  width = NAME_WIDTH));
+    }
+    summary
+}
+
+fn format_info(info: &PackInfo) -> String {
+    let mut lines = vec![format!("Pack #{} from {}", info.pack, info.bot)];
+    let fields = [
+        ("Filename", info.filename.clone()),
+        ("Size", info.size.map(|size| format!("{} ({} bytes)", format_bytes(size), size))),
+        ("MD5", info.md5.clone()),
+        ("CRC32", info.crc32.clone()),
+        ("Gets", info.gets.map(|gets| gets.to_string())),
+        ("Added", info.added.clone()),
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value {
+            lines.push(format!("  {:<9} {}", format!("{}:", name), value));
+        }
+    }
+    lines.join("\n")
+}
+
+fn format_bar(done: u64, total: u64) -> String {
+    let filled = if total == 0 { 0 } else { (done.min(total) as f64 / total as f64 * BAR_WIDTH as f64) as usize };
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+fn percentage(done: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { done as f64 / total as f64 * 100.0 }
+}
+
+struct TransferProgress {
+    id: usize,
+    name: String,
+    file_size: u64,
+    position: u64,
+    received: u64,
+    started_at: Instant,
+}
+
+impl TransferProgress {
+    fn line(&self) -> String {
+        let name: String = if self.name.chars().count() > NAME_WIDTH {
+            self.name.chars().take(NAME_WIDTH - 3).chain("...".chars()).collect()
+        } else {
+            self.name.clone()
+        };
+        // speed of this session, resumed bytes do not count
+        let elapsed = self.started_at.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 { (self.received - self.position) as f64 / elapsed } else { 0.0 };
+        let eta = if speed > 0.0 {
+            format_duration((self.file_size.saturating_sub(self.received) as f64 / speed) as u64)
+        } else {
+            "--:--".to_string()
+        };
+        format!("{:<width$} {} {:>5.1}% {:>10} / {:<10} {:>10}/s ETA {}",
+                name, format_bar(self.received, self.file_size), percentage(self.received, self.file_size),
+                format_bytes(self.received), format_bytes(self.file_size), format_bytes(speed as u64), eta,
+                width = NAME_WIDTH)
+    }
+}
+
+/// Shows the progress of all transfers and failures on stderr and prints finished files to stdout
+struct ProgressReporter {
+    transfers: Vec<TransferProgress>,
+    total_downloads: u32,
+    completed_downloads: u32,
+    completed_bytes: u64,
+    drawn_lines: usize,
+    enabled: bool,
+}
+
+impl ProgressReporter {
+    fn new() -> Self {
+        Self {
+            transfers: Vec::new(),
+            total_downloads: 0,
+            completed_downloads: 0,
+            completed_bytes: 0,
+            drawn_lines: 0,
+            // progress bars only make sense for humans watching
+            enabled: std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn remove(&mut self, id: usize) -> Option<TransferProgress> {
+        let index = self.transfers.iter().position(|transfer| transfer.id == id)?;
+        Some(self.transfers.remove(index))
+    }
+
+    fn complete(&mut self, path: String, bytes: u64) {
+        self.completed_downloads += 1;
+        self.completed_bytes += bytes;
+        self.clear();
+        println!("{}", path);
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Requested { packs, .. } => self.total_downloads += packs.len() as u32,
+            Event::Queued { bot, pack, position, total } => {
+                let total = total.map(|total| format!(" of {}", total)).unwrap_or_default();
+                self.clear();
+                eprintln!("Queued for {} from {} at position {}{}", format_pack(pack), bot, position, total);
+            }
+            Event::Retrying { bot, pack, retry, delay_seconds } => {
+                self.clear();
+                eprintln!("Requesting {} from {} again in {:.0}s (retry {})", format_pack(pack), bot, delay_seconds,
+                          retry);
+            }
+            Event::Reconnecting { attempt, delay_seconds } => {
+                self.clear();
+                eprintln!("Connection closed, reconnecting in {:.0}s (attempt {})", delay_seconds, attempt);
+            }
+            Event::PackFailed { bot, pack, reason } => {
+                self.completed_downloads += 1;
+                self.clear();
+                eprintln!("Giving up on {} from {}: {}", format_pack(pack), bot, reason);
+            }
+            Event::Info(info) => println!("{}", format_info(&info)),
+            // a single successful download is already reported by its file name
+            Event::Summary { results } if results.len() > 1 || results.iter().any(|r| !r.succeeded()) => {
+                self.clear();
+                eprint!("{}", format_summary(&results));
+            }
+            Event::Transfer(event) => self.handle_transfer(event),
+            _ => {}
+        }
+    }
+
+    fn handle_transfer(&mut self, event: TransferEvent) {
+        match event {
+            TransferEvent::Started { id, path, file_size, position } => {
+                self.transfers.push(TransferProgress {
+                    id,
+                    name: path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+                    file_size,
+                    position,
+                    received: position,
+                    started_at: Instant::now(),
+                });
+            }
+            TransferEvent::Progress { id, received } => {
+                if let Some(transfer) = self.transfers.iter_mut().find(|transfer| transfer.id == id) {
+                    transfer.received = received;
+                }
+            }
+            TransferEvent::Finished { id, path, .. } => {
+                let bytes = self.remove(id).map(|transfer| transfer.file_size).unwrap_or_default();
+                self.complete(path.display().to_string(), bytes);
+            }
+            TransferEvent::Skipped { id, path } => {
+                self.remove(id);
+                self.complete(path.display().to_string(), 0);
+            }
+            TransferEvent::Failed { id, reason } => {
+                // counted as completed once the pack is given up on
+                let name = self.remove(id).map(|transfer| transfer.name).unwrap_or_default();
+                self.clear();
+                eprintln!("Download of {} failed: {}", name, reason);
+            }
+        }
+    }
+
+    fn total_line(&self) -> String {
+        let total_bytes = self.completed_bytes + self.transfers.iter().map(|t| t.file_size).sum::<u64>();
+        let received_bytes = self.completed_bytes + self.transfers.iter().map(|t| t.received).sum::<u64>();
+        format!("{:<width$} {} {:>5.1}% {:>10} / {:<10} {}/{} packages",
+                "Total", format_bar(received_bytes, total_bytes), percentage(received_bytes, total_bytes),
+                format_bytes(received_bytes), format_bytes(total_bytes),
+                self.completed_downloads, self.total_downloads, width = NAME_WIDTH)
+    }
+
+    fn clear(&mut self) {
+        if self.enabled && self.drawn_lines > 0 {
+            // move to the first line we drew and clear everything below
+            eprint!("\x1b[{}A\x1b[J", self.drawn_lines);
+            self.drawn_lines = 0;
+        }
+    }
+
+    fn draw(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        let mut lines = self.transfers.iter().map(|transfer| transfer.line()).collect::<Vec<_>>();
+        if self.total_downloads > 1 {
+            lines.push(self.total_line());
+        }
+        self.clear();
+        let mut stderr = std::io::stderr().lock();
+        for line in &lines {
+            let _ = writeln!(stderr, "{}", line);
+        }
+        let _ = stderr.flush();
+        self.drawn_lines = lines.len();
+    }
+}
+
+/// Consumes events until all senders are dropped
+pub async fn report_progress(mut receiver: UnboundedReceiver<Event>) {
+    let mut reporter = ProgressReporter::new();
+    let mut interval = tokio::time::interval(REDRAW_INTERVAL);
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Some(event) => reporter.handle(event),
+                None => break,
+            },
+            _ = interval.tick() => reporter.draw(),
+        }
+    }
+    reporter.clear();
+}
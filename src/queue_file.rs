@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+
+use crate::irc::command::xdcc::Xdcc;
+use crate::package_downloader::DownloadCommand;
+
+/// A line of a queue, e.g. `/msg bot xdcc send #1 server=irc.example.net channel=#example output-dir=downloads`
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub command: Xdcc,
+    // IRC network of the bot, instead of --server
+    pub server: Option<String>,
+    // Channel to join for the bot, instead of --channel
+    pub channel: Option<String>,
+    // Directory to save the packs to, instead of --output-dir
+    pub output_dir: Option<PathBuf>,
+}
+
+impl TryFrom<&str> for QueueEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        let mut words = value.split_whitespace().collect::<Vec<_>>();
+        let (mut server, mut channel, mut output_dir) = (None, None, None);
+        // overrides follow the command
+        while let Some((name, setting)) = words.last().and_then(|word| word.split_once('=')) {
+            if setting.is_empty() {
+                bail!("Missing value for {} in {}", name, value);
+            }
+            match name {
+                "server" => server = Some(setting.to_string()),
+                "channel" => channel = Some(setting.to_string()),
+                "output-dir" => output_dir = Some(PathBuf::from(setting)),
+                _ => break,
+            }
+            words.pop();
+        }
+        Ok(Self {
+            command: Xdcc::try_from(words.join(" ").as_str())?,
+            server,
+            channel,
+            output_dir,
+        })
+    }
+}
+
+impl From<QueueEntry> for DownloadCommand {
+    fn from(value: QueueEntry) -> Self {
+        Self {
+            command: value.command,
+            output_dir: value.output_dir,
+        }
+    }
+}
+
+/// The packs of a server downloaded by previous runs, stored as one `server bot pack` line per pack
+#[derive(Debug, Clone)]
+pub struct StateFile {
+    path: PathBuf,
+    server: String,
+    done: HashSet<String>,
+}
+
+impl StateFile {
+    pub fn load(path: PathBuf, server: &str) -> Result<Self> {
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read state file {}", path.display())),
+        };
+        Ok(Self {
+            path,
+            server: server.to_lowercase(),
+            done: content.lines().map(|line| line.trim().to_string()).collect(),
+        })
+    }
+
+    fn key(&self, bot: &str, pack: Option<u32>) -> String {
+        let pack = pack.map(|pack| pack.to_string()).unwrap_or_else(|| "list".to_string());
+        // nicknames are case insensitive
+        format!("{} {} {}", self.server, bot.to_lowercase(), pack)
+    }
+
+    pub fn is_done(&self, bot: &str, pack: Option<u32>) -> bool {
+        self.done.contains(&self.key(bot, pack))
+    }
+
+    pub fn mark_done(&mut self, bot: &str, pack: Option<u32>) -> Result<()> {
+        let key = self.key(bot, pack);
+        if self.done.contains(&key) {
+            return Ok(());
+        }
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)
+            .with_context(|| format!("Failed to open state file {}", self.path.display()))?;
+        writeln!(file, "{}", key)?;
+        self.done.insert(key);
+        Ok(())
+    }
+
+    /// Removes the packs which are already done from the command, None if nothing is left to download
+    pub fn remaining(&self, command: Xdcc) -> Option<Xdcc> {
+        match command {
+            Xdcc::Send(bot, pack) if self.is_done(&bot, pack) => None,
+            Xdcc::List(bot) if self.is_done(&bot, None) => None,
+            Xdcc::Batch(bot, packs) => {
+                let packs = packs.into_iter().filter(|pack| !self.is_done(&bot, Some(*pack))).collect::<Vec<_>>();
+                if packs.is_empty() { None } else { Some(Xdcc::Batch(bot, packs)) }
+            }
+            command => Some(command),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(line: &str) -> QueueEntry {
+        QueueEntry::try_from(line).unwrap()
+    }
+
+    fn state_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("xdcc-cli-test-{}-{}.state", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn parses_overrides() {
+        let plain = entry("/msg bot xdcc send #1");
+        assert!(matches!(plain.command, Xdcc::Send(ref bot, Some(1)) if bot == "bot"));
+        assert!(plain.server.is_none() && plain.channel.is_none() && plain.output_dir.is_none());
+
+        let all = entry("/msg bot xdcc batch 1-3 server=irc.example.net channel=#example output-dir=downloads");
+        assert!(matches!(all.command, Xdcc::Batch(_, ref packs) if packs == &[1, 2, 3]));
+        assert_eq!(all.server.as_deref(), Some("irc.example.net"));
+        assert_eq!(all.channel.as_deref(), Some("#example"));
+        assert_eq!(all.output_dir, Some(PathBuf::from("downloads")));
+
+        // in any order, the first one of repeated overrides wins
+        let reordered = entry("/msg bot xdcc send #1  output-dir=a channel=#a channel=#b");
+        assert_eq!(reordered.channel.as_deref(), Some("#a"));
+        assert_eq!(reordered.output_dir, Some(PathBuf::from("a")));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(QueueEntry::try_from("").is_err());
+        assert!(QueueEntry::try_from("server=irc.example.net").is_err());
+        assert!(QueueEntry::try_from("/msg bot xdcc send #1 server=").is_err());
+        assert!(QueueEntry::try_from("/msg bot xdcc send #1 channel= server=irc.example.net").is_err());
+        // unknown settings are part of the command, which does not parse
+        assert!(QueueEntry::try_from("/msg bot xdcc send #x speed=fast").is_err());
+    }
+
+    #[test]
+    fn records_finished_packs() {
+        let path = state_path("round-trip");
+        let mut state = StateFile::load(path.clone(), "irc.example.net").unwrap();
+        assert!(!state.is_done("bot", Some(1)));
+        state.mark_done("Bot", Some(1)).unwrap();
+        state.mark_done("bot", Some(1)).unwrap();
+        state.mark_done("bot", None).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "irc.example.net bot 1\nirc.example.net bot list\n");
+
+        // nicknames and servers ignore case, other servers have their own packs
+        let state = StateFile::load(path.clone(), "IRC.example.net").unwrap();
+        assert!(state.is_done("BOT", Some(1)) && state.is_done("bot", None));
+        assert!(!state.is_done("bot", Some(2)));
+        assert!(!StateFile::load(path.clone(), "irc.other.net").unwrap().is_done("bot", Some(1)));
+
+        assert!(state.remaining(Xdcc::Send("bot".to_string(), Some(1))).is_none());
+        assert!(state.remaining(Xdcc::List("bot".to_string())).is_none());
+        let batch = state.remaining(Xdcc::Batch("bot".to_string(), vec![1, 2, 3]));
+        assert!(matches!(batch, Some(Xdcc::Batch(_, packs)) if packs == vec![2, 3]));
+        assert!(state.remaining(Xdcc::Batch("bot".to_string(), vec![1])).is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ignores_malformed_state_lines() {
+        let path = state_path("malformed");
+        std::fs::write(&path, "\n  irc.example.net bot 1  \ngarbage\nirc.example.net bot\n").unwrap();
+        let state = StateFile::load(path.clone(), "irc.example.net").unwrap();
+        assert!(state.is_done("bot", Some(1)));
+        assert!(!state.is_done("bot", Some(2)));
+        std::fs::remove_file(&path).unwrap();
+        // a missing file is an empty state
+        assert!(!StateFile::load(path, "irc.example.net").unwrap().is_done("bot", Some(1)));
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+/// Outcome of a single requested pack
+#[derive(Debug, Clone, Serialize)]
+pub struct PackResult {
+    pub bot: String,
+    pub pack: Option<u32>,
+    // Name of the file as offered by the bot, unknown if it never sent an offer
+    pub filename: Option<String>,
+    pub path: Option<PathBuf>,
+    // Size of the downloaded file, for failed packs the part we received
+    pub bytes: u64,
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub timed_out: bool,
+}
+
+impl PackResult {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Why a run did not succeed, each reason has its own exit code
+#[derive(Debug)]
+pub enum Failure {
+    // The command could not be parsed or is not supported
+    BadCommand(String),
+    // Some or all packs could not be downloaded
+    PacksFailed { failed: usize, total: usize },
+    // The bot did not send the failed packs in time
+    Timeout { failed: usize, total: usize },
+    // The connection to the IRC network was closed and packs could not be downloaded because of it
+    ConnectionLost,
+}
+
+impl Failure {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Failure::BadCommand(_) => 2,
+            Failure::PacksFailed { .. } => 3,
+            Failure::Timeout { .. } => 4,
+            Failure::ConnectionLost => 5,
+        }
+    }
+
+    /// Picks the failure matching the results of all packs, if any pack failed
+    pub fn from_results(results: &[PackResult]) -> Option<Self> {
+        let failed = results.iter().filter(|result| !result.succeeded()).count();
+        let total = results.len();
+        if failed == 0 {
+            None
+        } else if results.iter().all(|result| result.succeeded() || result.timed_out) {
+            Some(Failure::Timeout { failed, total })
+        } else {
+            Some(Failure::PacksFailed { failed, total })
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::BadCommand(reason) => write!(f, "{}", reason),
+            Failure::PacksFailed { failed, total } => write!(f, "{} of {} packages failed to download!", failed, total),
+            Failure::Timeout { failed, total } => write!(f, "Timed out waiting for {} of {} packages!", failed, total),
+            Failure::ConnectionLost => write!(f, "Connection closed before all packages were downloaded!"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}